@@ -26,11 +26,11 @@ pub struct TraceContextLayer<S> {
     _registry: std::marker::PhantomData<S>,
 }
 
+#[non_exhaustive]
 pub struct TraceContext {
     pub span_id: Uuid,
     pub parent_id: Option<Uuid>,
     pub trace: Trace,
-    _hidden: (),
 }
 
 #[derive(Debug)]
@@ -77,7 +77,6 @@ impl TraceContext {
             span_id: Uuid::new_v4(),
             parent_id: None,
             trace: Trace::new(),
-            _hidden: (),
         }
     }
 
@@ -86,7 +85,6 @@ impl TraceContext {
             span_id: Uuid::new_v4(),
             parent_id: Some(self.span_id),
             trace: self.trace.clone(),
-            _hidden: (),
         }
     }
 }
@@ -151,7 +149,7 @@ mod tests {
             dispatcher::get_default(|d| {
                 let registry = d.downcast_ref::<Registry>().unwrap();
                 let span = d.current_span();
-                let spanref = registry.span(&span.id().unwrap()).unwrap();
+                let spanref = registry.span(span.id().unwrap()).unwrap();
                 let extensions = spanref.extensions();
                 let trace_context = extensions.get::<TraceContext>().unwrap();
                 assert!(trace_context.parent_id.is_none());
@@ -163,7 +161,7 @@ mod tests {
                 dispatcher::get_default(|d| {
                     let registry = d.downcast_ref::<Registry>().unwrap();
                     let span = d.current_span();
-                    let spanref = registry.span(&span.id().unwrap()).unwrap();
+                    let spanref = registry.span(span.id().unwrap()).unwrap();
                     let extensions = spanref.extensions();
                     let trace_context = extensions.get::<TraceContext>().unwrap();
                     assert_eq!(trace_context.parent_id, Some(root));
@@ -0,0 +1,245 @@
+use crate::opentelemetry::sampler::BufferedSpan;
+use crate::Trace;
+use opentelemetry::trace::Event;
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The longest time between two sweeps for expired traces.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The limit on the span data buffered across all in-flight traces.
+///
+/// Set with [`OpenTelemetryLayer::with_memory_budget`].
+///
+/// [`OpenTelemetryLayer::with_memory_budget`]: crate::opentelemetry::OpenTelemetryLayer::with_memory_budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryBudget {
+    /// At most this many spans are buffered.
+    Spans(usize),
+    /// At most about this many bytes of span data are buffered.
+    ///
+    /// The size of a span is estimated from its fields, attributes, events and links, ignoring
+    /// the heap allocations of strings.
+    Bytes(usize),
+}
+
+/// Which traces are evicted first once the [`MemoryBudget`] is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionOrder {
+    /// The traces which started buffering first.
+    OldestFirst,
+    /// The traces buffering the most data.
+    LargestFirst,
+}
+
+/// What happens to the traces evicted once the [`MemoryBudget`] is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionAction {
+    /// Discard the trace, including the spans closing after the eviction.
+    Drop,
+    /// Decide the trace right away, from the spans buffered so far, then export or discard its
+    /// spans as they close.
+    Flush,
+}
+
+/// The traces with buffered spans, and the size of their buffers.
+pub(crate) struct InFlight {
+    state: Mutex<State>,
+    epoch: Instant,
+    /// When the next sweep for expired traces is due, in nanoseconds since `epoch`.
+    next_sweep: AtomicU64,
+}
+
+impl Default for InFlight {
+    fn default() -> Self {
+        InFlight {
+            state: Mutex::default(),
+            epoch: Instant::now(),
+            next_sweep: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    traces: HashMap<Uuid, Entry>,
+    total: usize,
+}
+
+struct Entry {
+    trace: Trace,
+    since: Instant,
+    size: usize,
+}
+
+impl InFlight {
+    /// Records that `trace` buffers `size` spans or bytes.
+    pub(crate) fn update(&self, trace: &Trace, size: usize) {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        let entry = state.traces.entry(*trace.id()).or_insert_with(|| Entry {
+            trace: trace.clone(),
+            since: Instant::now(),
+            size: 0,
+        });
+        let previous = mem::replace(&mut entry.size, size);
+        state.total = state.total - previous + size;
+    }
+
+    /// Records that `trace` no longer buffers spans.
+    pub(crate) fn remove(&self, trace: &Trace) {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        if let Some(entry) = state.traces.remove(trace.id()) {
+            state.total -= entry.size;
+        }
+    }
+
+    /// Removes traces in `order` until at most `limit` spans or bytes are buffered, and returns
+    /// them.
+    pub(crate) fn evict(&self, limit: usize, order: EvictionOrder) -> Vec<Trace> {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        if state.total <= limit {
+            return Vec::new();
+        }
+
+        let mut candidates: Vec<(Uuid, Instant, usize)> = state
+            .traces
+            .iter()
+            .map(|(id, entry)| (*id, entry.since, entry.size))
+            .collect();
+        match order {
+            EvictionOrder::OldestFirst => candidates.sort_by_key(|(_, since, _)| *since),
+            EvictionOrder::LargestFirst => {
+                candidates.sort_by_key(|(_, _, size)| std::cmp::Reverse(*size))
+            }
+        }
+
+        let mut evicted = Vec::new();
+        for (id, _, _) in candidates {
+            if state.total <= limit {
+                break;
+            }
+            if let Some(entry) = state.traces.remove(&id) {
+                state.total -= entry.size;
+                evicted.push(entry.trace);
+            }
+        }
+        evicted
+    }
+
+    /// Removes the traces buffering spans for at least `max_age` at `now`, and returns them.
+    ///
+    /// Sweeps happen at most once per tenth of `max_age`, and at least once per second. Until a
+    /// sweep is due, no lock is taken, so that callers can check for expired traces on every span.
+    pub(crate) fn expired(&self, max_age: Duration, now: Instant) -> Vec<Trace> {
+        let elapsed = now.saturating_duration_since(self.epoch).as_nanos() as u64;
+        if elapsed < self.next_sweep.load(Ordering::Relaxed) {
+            return Vec::new();
+        }
+
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        // Another caller may have swept in the meantime.
+        if elapsed < self.next_sweep.load(Ordering::Relaxed) {
+            return Vec::new();
+        }
+        let interval = (max_age / 10).min(SWEEP_INTERVAL);
+        self.next_sweep
+            .store(elapsed + interval.as_nanos() as u64, Ordering::Relaxed);
+
+        let expired: Vec<Uuid> = state
+            .traces
+            .iter()
+            .filter(|(_, entry)| now.saturating_duration_since(entry.since) >= max_age)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut traces = Vec::with_capacity(expired.len());
+        for id in expired {
+            if let Some(entry) = state.traces.remove(&id) {
+                state.total -= entry.size;
+                traces.push(entry.trace);
+            }
+        }
+        traces
+    }
+
+    #[cfg(test)]
+    pub(crate) fn total(&self) -> usize {
+        self.state.lock().expect("Mutex poisoned").total
+    }
+}
+
+/// Estimates the memory held by a buffered span.
+pub(crate) fn approximate_size(span: &BufferedSpan) -> usize {
+    let builder = &span.data.builder;
+    let events = builder.events.iter().flatten();
+    let event_attributes: usize = events.clone().map(|event| event.attributes.len()).sum();
+
+    mem::size_of::<BufferedSpan>()
+        + builder.attributes.as_ref().map_or(0, Vec::len) * mem::size_of::<KeyValue>()
+        + events.count() * mem::size_of::<Event>()
+        + event_attributes * mem::size_of::<KeyValue>()
+        + builder.links.as_ref().map_or(0, Vec::len) * mem::size_of::<opentelemetry::trace::Link>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_in_order() {
+        let (old, large, small) = (Trace::new(), Trace::new(), Trace::new());
+        let in_flight = InFlight::default();
+        in_flight.update(&old, 2);
+        in_flight.update(&large, 5);
+        in_flight.update(&small, 1);
+        in_flight.update(&old, 3);
+        assert_eq!(in_flight.total(), 9);
+
+        assert!(in_flight.evict(9, EvictionOrder::OldestFirst).is_empty());
+
+        let evicted = in_flight.evict(6, EvictionOrder::OldestFirst);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].id(), old.id());
+        assert_eq!(in_flight.total(), 6);
+
+        let evicted = in_flight.evict(5, EvictionOrder::LargestFirst);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].id(), large.id());
+
+        in_flight.remove(&small);
+        in_flight.remove(&small);
+        assert_eq!(in_flight.total(), 0);
+    }
+
+    #[test]
+    fn expires_old_traces() {
+        let (old, new) = (Trace::new(), Trace::new());
+        let in_flight = InFlight::default();
+        in_flight.update(&old, 1);
+        let start = Instant::now();
+        let max_age = Duration::from_secs(10);
+
+        assert!(in_flight.expired(max_age, start).is_empty());
+        in_flight.update(&new, 1);
+
+        let later = start + Duration::from_secs(10);
+        let expired = in_flight.expired(max_age, later);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id(), old.id());
+
+        // Sweeps are spaced out.
+        assert!(in_flight
+            .expired(max_age, later + Duration::from_millis(500))
+            .is_empty());
+
+        let expired = in_flight.expired(max_age, later + Duration::from_secs(1));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id(), new.id());
+        assert_eq!(in_flight.total(), 0);
+    }
+}
@@ -25,6 +25,12 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
 // IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
+use crate::opentelemetry::in_flight::{
+    approximate_size, EvictionAction, EvictionOrder, InFlight, MemoryBudget,
+};
+use crate::opentelemetry::sampler::{
+    self, BufferedSpan, BufferedTrace, Decision, SpanPruner, TailSampler, TraceView,
+};
 use crate::opentelemetry::{OtelData, PreSampledTracer};
 use opentelemetry::{
     trace::{self as otel, noop, TraceContextExt},
@@ -33,7 +39,9 @@ use opentelemetry::{
 use std::collections::VecDeque;
 use std::fmt;
 use std::marker;
-use std::time::{Instant, SystemTime};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use std::{any::TypeId, borrow::Cow};
 use tracing_core::span::{self, Attributes, Id, Record};
 use tracing_core::{field, Event, Subscriber};
@@ -43,33 +51,204 @@ use tracing_subscriber::layer::Context;
 use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::Layer;
 
-use crate::{SampleDecision, TraceContext};
+use crate::{SampleDecision, Trace, TraceContext};
 
 #[derive(Default)]
 struct TraceCache {
-    spans: VecDeque<OtelData>,
+    spans: VecDeque<BufferedSpan>,
+    /// The approximate size of the buffered spans.
+    bytes: usize,
+    /// Whether the trace hit the span cap.
+    truncated: bool,
+    /// The decision made for the trace, early or as its root closed.
+    decided: Option<bool>,
+    /// Whether the root span of the trace closed.
+    root_closed: bool,
+    /// The size last reported to the in-flight traces, if the trace is tracked.
+    tracked: Option<usize>,
+    /// How many traces the spans of a trace kept before its root closed stand for.
+    adjusted_count: Option<f64>,
+    /// Why a trace kept before its root closed was kept, recorded on the root once it closes.
+    root_labels: Vec<KeyValue>,
+    /// The summary of a trace dropped before its root closed, completed as its spans close.
+    summary: Option<Summary>,
 }
 
 impl TraceCache {
+    fn push(&mut self, span: BufferedSpan) {
+        self.bytes += approximate_size(&span);
+        self.spans.push_back(span);
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some(span) = self.spans.pop_front() {
+            self.bytes = self.bytes.saturating_sub(approximate_size(&span));
+        }
+    }
+
+    /// Samples the buffered spans, which lack the root span if the trace is `partial`.
+    fn sample(&mut self, sampler: &dyn TailSampler, partial: bool) -> Decision {
+        let spans = self.spans.make_contiguous();
+        if partial {
+            sampler.sample(&BufferedTrace::partial(spans))
+        } else {
+            sampler.sample(&BufferedTrace::new(spans))
+        }
+    }
+
+    /// Records why the trace was kept on its root span, and how many traces it stands for on
+    /// every span. The root span of a `partial` trace records them once it closes.
+    fn record_decision(&mut self, decision: &Decision, partial: bool) {
+        if let Some(adjusted_count) = decision.adjusted_count() {
+            for span in &mut self.spans {
+                let attributes = span.data.builder.attributes.get_or_insert_with(Vec::new);
+                attributes.push(KeyValue::new(SAMPLING_ADJUSTED_COUNT, adjusted_count));
+            }
+        }
+
+        let mut labels = Vec::new();
+        if let Some(policy) = decision.policy() {
+            labels.push(KeyValue::new(SAMPLING_POLICY, policy.to_string()));
+        }
+        if let Some(rule) = decision.rule() {
+            labels.push(KeyValue::new(SAMPLING_RULE, rule.to_string()));
+        }
+
+        if partial {
+            self.adjusted_count = decision.adjusted_count();
+            self.root_labels = labels;
+        } else if let Some(root) = self.spans.back_mut() {
+            let attributes = root.data.builder.attributes.get_or_insert_with(Vec::new);
+            attributes.extend(labels);
+        }
+    }
+
+    /// Records the decision made before the root span closed on a span closing after it.
+    fn record_late_decision(&mut self, builder: &mut otel::SpanBuilder, is_root: bool) {
+        let attributes = builder.attributes.get_or_insert_with(Vec::new);
+        if let Some(adjusted_count) = self.adjusted_count {
+            attributes.push(KeyValue::new(SAMPLING_ADJUSTED_COUNT, adjusted_count));
+        }
+        if is_root {
+            attributes.append(&mut self.root_labels);
+        }
+    }
+
     fn send_trace<T>(&mut self, tracer: &T)
     where
         T: otel::Tracer + PreSampledTracer + 'static,
     {
-        let trace_spans = std::mem::replace(&mut self.spans, Default::default());
+        let trace_spans = std::mem::take(&mut self.spans);
+        self.bytes = 0;
 
-        for OtelData { builder, parent_cx } in trace_spans {
-            builder.start_with_context(tracer, &parent_cx);
+        for BufferedSpan { data, .. } in trace_spans {
+            data.builder.start_with_context(tracer, &data.parent_cx);
         }
     }
 
+    /// Exports the root span only, recording aggregates of the whole trace.
+    fn send_summary<T>(&mut self, tracer: &T)
+    where
+        T: otel::Tracer + PreSampledTracer + 'static,
+    {
+        let mut summary = Summary::default();
+        for span in &self.spans {
+            summary.add(&span.data.builder);
+        }
+
+        let mut root = match self.spans.pop_back() {
+            Some(root) => root,
+            None => return,
+        };
+        self.clear();
+
+        summary.record(&mut root.data.builder);
+        root.data
+            .builder
+            .start_with_context(tracer, &root.data.parent_cx);
+    }
+
+    /// Releases the buffered spans of a trace dropped before its root closed, keeping their
+    /// aggregates for the summary exported with the root.
+    fn start_summary(&mut self) {
+        let mut summary = Summary::default();
+        for span in &self.spans {
+            summary.add(&span.data.builder);
+        }
+        self.summary = Some(summary);
+        self.clear();
+    }
+
     fn clear(&mut self) {
-        drop(std::mem::replace(&mut self.spans, Default::default()));
+        drop(std::mem::take(&mut self.spans));
+        self.bytes = 0;
     }
 }
 
 const SPAN_NAME_FIELD: &str = "otel.name";
 const SPAN_KIND_FIELD: &str = "otel.kind";
 const SPAN_STATUS: &str = "otel.status";
+const SAMPLING_POLICY: &str = "sampling.policy";
+const SAMPLING_RULE: &str = "sampling.rule";
+const SAMPLING_ADJUSTED_COUNT: &str = "sampling.adjusted_count";
+const SAMPLING_SUMMARY: &str = "sampling.summary";
+const TRACE_SPAN_COUNT: &str = "trace.span_count";
+const TRACE_ERROR_COUNT: &str = "trace.error_count";
+const TRACE_DURATION: &str = "trace.duration_ns";
+const TRACE_TRUNCATED: &str = "trace.truncated";
+
+/// What happens to the spans of a trace closing once it buffers the maximum number of spans.
+///
+/// Set with [`OpenTelemetryLayer::with_max_spans_per_trace`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanOverflow {
+    /// Discard the span that just closed.
+    DropNewest,
+    /// Discard the oldest buffered span to make room for the span that just closed.
+    DropOldest,
+    /// Decide the trace right away, then export or discard its spans as they close.
+    ///
+    /// The sampler sees a partial trace, of the spans buffered so far without the root.
+    Flush,
+}
+
+/// Counts the spans closing after the root span of their trace, once the trace was flushed.
+///
+/// The [`Registry`] keeps a span open until its children close, but other subscribers may close
+/// the root span of a trace first. Such late spans are exported or discarded according to the
+/// decision made for their trace. Spans closing before the root of a trace decided early, e.g.
+/// with a [`SampleDecision`], follow the decision too, but are not late. Obtained with
+/// [`OpenTelemetryLayer::late_spans`], and shared by all clones.
+///
+/// [`Registry`]: tracing_subscriber::Registry
+/// [`SampleDecision`]: crate::SampleDecision
+#[derive(Debug, Clone, Default)]
+pub struct LateSpans {
+    exported: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl LateSpans {
+    /// The number of late spans exported, as their trace was kept.
+    pub fn exported(&self) -> u64 {
+        self.exported.load(Ordering::Relaxed)
+    }
+
+    /// The number of late spans discarded, as their trace was dropped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, exported: bool) {
+        let counter = if exported {
+            &self.exported
+        } else {
+            &self.dropped
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 /// An [OpenTelemetry] propagation layer for use in a project that uses
 /// [tracing].
 ///
@@ -78,7 +257,16 @@ const SPAN_STATUS: &str = "otel.status";
 pub struct OpenTelemetryLayer<S, T> {
     tracer: T,
     tracked_inactivity: bool,
+    tail_sampler: Option<Box<dyn TailSampler>>,
+    span_pruner: Option<SpanPruner>,
+    summarize_dropped: bool,
+    span_cap: Option<(usize, SpanOverflow)>,
+    memory_budget: Option<(MemoryBudget, EvictionOrder, EvictionAction)>,
+    max_trace_age: Option<Duration>,
+    in_flight: InFlight,
+    late_spans: LateSpans,
     get_context: WithContext,
+    reap_expired: ReapExpired,
     _registry: marker::PhantomData<S>,
 }
 
@@ -118,6 +306,7 @@ where
 // types at the callsite.
 //
 // See https://github.com/tokio-rs/tracing/blob/4dad420ee1d4607bad79270c1520673fa6266a3d/tracing-error/src/layer.rs
+#[allow(clippy::type_complexity)]
 pub(crate) struct WithContext(
     fn(&tracing::Dispatch, &span::Id, f: &mut dyn FnMut(&mut OtelData, &dyn PreSampledTracer)),
 );
@@ -125,9 +314,9 @@ pub(crate) struct WithContext(
 impl WithContext {
     // This function allows a function to be called in the context of the
     // "remembered" subscriber.
-    pub(crate) fn with_context<'a>(
+    pub(crate) fn with_context(
         &self,
-        dispatch: &'a tracing::Dispatch,
+        dispatch: &tracing::Dispatch,
         id: &span::Id,
         mut f: impl FnMut(&mut OtelData, &dyn PreSampledTracer),
     ) {
@@ -135,6 +324,45 @@ impl WithContext {
     }
 }
 
+// Like `WithContext`, "remembers" the types of the subscriber, to decide expired traces from
+// outside the layer.
+pub(crate) struct ReapExpired(fn(&tracing::Dispatch));
+
+/// Decides the traces which outlived the maximum trace age of the [`OpenTelemetryLayer`] of
+/// `dispatch`, if any.
+///
+/// Expired traces are otherwise only looked for as spans open and close. Calling this function
+/// periodically decides them even once no span opens or closes anymore, e.g. in a process
+/// holding long-lived connections open.
+///
+/// # Examples
+///
+/// ```no_run
+/// use onesignal_tracing_tail_sample::opentelemetry::reap_expired_traces;
+/// use onesignal_tracing_tail_sample::TraceContextLayer;
+/// use std::time::Duration;
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// let telemetry = onesignal_tracing_tail_sample::opentelemetry::layer()
+///     .with_max_trace_age(Duration::from_secs(600));
+/// let subscriber = Registry::default()
+///     .with(TraceContextLayer::default())
+///     .with(telemetry);
+/// let dispatch = tracing::Dispatch::new(subscriber);
+/// tracing::dispatcher::set_global_default(dispatch.clone()).unwrap();
+///
+/// std::thread::spawn(move || loop {
+///     std::thread::sleep(Duration::from_secs(10));
+///     reap_expired_traces(&dispatch);
+/// });
+/// ```
+pub fn reap_expired_traces(dispatch: &tracing::Dispatch) {
+    if let Some(reap_expired) = dispatch.downcast_ref::<ReapExpired>() {
+        (reap_expired.0)(dispatch);
+    }
+}
+
 fn str_to_span_kind(s: &str) -> Option<otel::SpanKind> {
     match s {
         s if s.eq_ignore_ascii_case("server") => Some(otel::SpanKind::Server),
@@ -373,7 +601,16 @@ where
         OpenTelemetryLayer {
             tracer,
             tracked_inactivity: true,
+            tail_sampler: None,
+            span_pruner: None,
+            summarize_dropped: false,
+            span_cap: None,
+            memory_budget: None,
+            max_trace_age: None,
+            in_flight: InFlight::default(),
+            late_spans: LateSpans::default(),
             get_context: WithContext(Self::get_context),
+            reap_expired: ReapExpired(Self::reap_expired_in),
             _registry: marker::PhantomData,
         }
     }
@@ -416,7 +653,16 @@ where
         OpenTelemetryLayer {
             tracer,
             tracked_inactivity: self.tracked_inactivity,
+            tail_sampler: self.tail_sampler,
+            span_pruner: self.span_pruner,
+            summarize_dropped: self.summarize_dropped,
+            span_cap: self.span_cap,
+            memory_budget: self.memory_budget,
+            max_trace_age: self.max_trace_age,
+            in_flight: self.in_flight,
+            late_spans: self.late_spans,
             get_context: WithContext(OpenTelemetryLayer::<S, Tracer>::get_context),
+            reap_expired: ReapExpired(OpenTelemetryLayer::<S, Tracer>::reap_expired_in),
             _registry: self._registry,
         }
     }
@@ -431,6 +677,183 @@ where
         }
     }
 
+    /// Sets the [`TailSampler`] that decides whether a buffered trace is exported once its root
+    /// span closes.
+    ///
+    /// Traces are only buffered when a [`TraceContextLayer`] is installed below this layer. A
+    /// [`SampleDecision`] inserted into the trace's extensions takes precedence over the sampler.
+    /// Without a sampler, every trace is exported.
+    ///
+    /// The root span of a kept trace records the policy and rule of the [`Decision`] as the
+    /// `sampling.policy` and `sampling.rule` attributes. Spans of traces kept with a probability
+    /// below 1 record the inverse of that probability as `sampling.adjusted_count`.
+    ///
+    /// [`TraceContextLayer`]: crate::TraceContextLayer
+    pub fn with_tail_sampler<P>(self, tail_sampler: P) -> Self
+    where
+        P: TailSampler + 'static,
+    {
+        Self {
+            tail_sampler: Some(Box::new(tail_sampler)),
+            ..self
+        }
+    }
+
+    /// Sets a closure deciding whether a buffered trace is exported once its root span closes,
+    /// from a read-only [`TraceView`] of the trace.
+    ///
+    /// This is a shorthand for [`with_tail_sampler`] with a [`sampler::from_fn`] sampler.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use onesignal_tracing_tail_sample::opentelemetry::sampler::Decision;
+    /// use onesignal_tracing_tail_sample::TraceContextLayer;
+    /// use opentelemetry::trace::Status;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// // Keep traces where a direct child of the root failed.
+    /// let telemetry = onesignal_tracing_tail_sample::opentelemetry::layer().with_sampler(|trace| {
+    ///     let failed = |status: &Status| matches!(status, Status::Error { .. });
+    ///     let mut children = trace.root().into_iter().flat_map(|root| root.children());
+    ///     if children.any(|span| failed(span.status())) {
+    ///         Decision::keep().with_policy("child_failed")
+    ///     } else {
+    ///         Decision::drop()
+    ///     }
+    /// });
+    ///
+    /// let subscriber = Registry::default()
+    ///     .with(TraceContextLayer::default())
+    ///     .with(telemetry);
+    /// # drop(subscriber);
+    /// ```
+    ///
+    /// [`with_tail_sampler`]: OpenTelemetryLayer::with_tail_sampler
+    pub fn with_sampler<F>(self, f: F) -> Self
+    where
+        F: Fn(&TraceView<'_>) -> Decision + Send + Sync + 'static,
+    {
+        self.with_tail_sampler(sampler::from_fn(f))
+    }
+
+    /// Sets the [`SpanPruner`] removing low-value spans from the buffered traces that are kept.
+    pub fn with_span_pruner(self, span_pruner: SpanPruner) -> Self {
+        Self {
+            span_pruner: Some(span_pruner),
+            ..self
+        }
+    }
+
+    /// Sets whether the root span of traces dropped by the [`TailSampler`] is still exported, as
+    /// a summary of the trace.
+    ///
+    /// The summary root records `sampling.summary = true`, along with the number of spans and of
+    /// spans with an error status in the trace as `trace.span_count` and `trace.error_count`, and
+    /// the time between the first span starting and the last one ending as `trace.duration_ns`.
+    /// Traces dropped before their root closed, e.g. by the application with a
+    /// [`SampleDecision`], are summarized as well, once their root closes.
+    ///
+    /// [`SampleDecision`]: crate::SampleDecision
+    pub fn with_dropped_trace_summaries(self, summarize_dropped: bool) -> Self {
+        Self {
+            summarize_dropped,
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of spans buffered for a single trace, besides its root, and what
+    /// happens to the spans closing past that number.
+    ///
+    /// The root of a trace which hit the cap records `trace.truncated = true`, whatever the
+    /// overflow strategy. With [`SpanOverflow::Flush`], no span is lost, but the trace is decided
+    /// from the spans buffered up to the cap.
+    pub fn with_max_spans_per_trace(self, max_spans: usize, overflow: SpanOverflow) -> Self {
+        Self {
+            span_cap: Some((max_spans, overflow)),
+            ..self
+        }
+    }
+
+    /// Sets a limit on the span data buffered across all in-flight traces.
+    ///
+    /// Once the budget is exceeded, whole traces are evicted in `order` until the buffered data
+    /// fits the budget again. Evicted traces are dropped or decided early according to `action`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use onesignal_tracing_tail_sample::opentelemetry::{EvictionAction, EvictionOrder, MemoryBudget};
+    /// use onesignal_tracing_tail_sample::TraceContextLayer;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// // Drop the largest traces rather than buffering more than 256MiB of spans.
+    /// let telemetry = onesignal_tracing_tail_sample::opentelemetry::layer().with_memory_budget(
+    ///     MemoryBudget::Bytes(256 * 1024 * 1024),
+    ///     EvictionOrder::LargestFirst,
+    ///     EvictionAction::Drop,
+    /// );
+    ///
+    /// let subscriber = Registry::default()
+    ///     .with(TraceContextLayer::default())
+    ///     .with(telemetry);
+    /// # drop(subscriber);
+    /// ```
+    pub fn with_memory_budget(
+        self,
+        budget: MemoryBudget,
+        order: EvictionOrder,
+        action: EvictionAction,
+    ) -> Self {
+        Self {
+            memory_budget: Some((budget, order, action)),
+            ..self
+        }
+    }
+
+    /// Sets how long the spans of a trace are buffered at most, for traces whose root span stays
+    /// open for too long, or never closes.
+    ///
+    /// Once a trace has buffered spans for `max_age`, counted from its first buffered span, it is
+    /// decided from the spans buffered so far, which are exported or discarded. Its later spans
+    /// are then exported or discarded as they close, according to that decision.
+    ///
+    /// Expired traces are looked for as spans open and close, at most every tenth of `max_age` and
+    /// every second, so a trace may be decided slightly after it expires. Between sweeps, checking
+    /// for expired traces takes no lock. A process in which spans may stop opening and closing
+    /// should also call [`reap_expired_traces`] periodically, e.g. from a timer.
+    pub fn with_max_trace_age(self, max_age: Duration) -> Self {
+        Self {
+            max_trace_age: Some(max_age),
+            ..self
+        }
+    }
+
+    /// Returns the counters of the spans closing after the root span of their trace.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use onesignal_tracing_tail_sample::TraceContextLayer;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let telemetry = onesignal_tracing_tail_sample::opentelemetry::layer();
+    /// let late_spans = telemetry.late_spans();
+    /// let subscriber = Registry::default()
+    ///     .with(TraceContextLayer::default())
+    ///     .with(telemetry);
+    /// # drop(subscriber);
+    ///
+    /// // Later, e.g. when reporting metrics.
+    /// println!("{} late spans exported", late_spans.exported());
+    /// ```
+    pub fn late_spans(&self) -> LateSpans {
+        self.late_spans.clone()
+    }
+
     /// Retrieve the parent OpenTelemetry [`Context`] from the current tracing
     /// [`span`] through the [`Registry`]. This [`Context`] links spans to their
     /// parent for proper hierarchical visualization.
@@ -463,6 +886,204 @@ where
         }
     }
 
+    /// Applies the decision made for `trace` before its root closed, if any.
+    ///
+    /// The first time a decision made by the application is seen, the spans buffered so far are
+    /// flushed according to it. Either way, later spans of the trace are no longer buffered.
+    fn early_decision(&self, trace: &Trace) -> Option<bool> {
+        let mut trace_ext = trace.extensions_mut();
+        let app_decision = trace_ext
+            .get_mut::<SampleDecision>()
+            .map(|decision| decision.record_trace);
+        let decided = trace_ext
+            .get_mut::<TraceCache>()
+            .and_then(|cache| cache.decided);
+        let record_trace = match app_decision {
+            Some(record_trace) if decided != Some(record_trace) => record_trace,
+            _ => return decided,
+        };
+
+        if trace_ext.get_mut::<TraceCache>().is_none() {
+            trace_ext.insert(TraceCache::default());
+        }
+        let cache = trace_ext
+            .get_mut::<TraceCache>()
+            .expect("Cache not found, this is a bug");
+        let decision = if record_trace {
+            Decision::keep()
+        } else {
+            Decision::drop()
+        };
+        self.decide_early(cache, &decision);
+        self.track(trace, cache);
+        Some(record_trace)
+    }
+
+    /// Flushes a trace decided before its root closed, from the spans buffered so far. Later
+    /// spans of the trace follow the decision as they close.
+    fn decide_early(&self, cache: &mut TraceCache, decision: &Decision) {
+        self.flush(cache, decision, true);
+        cache.decided = Some(decision.record_trace());
+    }
+
+    /// Keeps track of the size of the buffer of `trace`, when a memory budget or a maximum trace
+    /// age is set. The in-flight traces are only updated as the size changes.
+    fn track(&self, trace: &Trace, cache: &mut TraceCache) {
+        let size = match self.memory_budget {
+            Some((MemoryBudget::Bytes(_), _, _)) => cache.bytes,
+            Some((MemoryBudget::Spans(_), _, _)) => cache.spans.len(),
+            // Only the age of the trace matters.
+            None if self.max_trace_age.is_some() => 0,
+            None => return,
+        };
+
+        if cache.spans.is_empty() {
+            if cache.tracked.take().is_some() {
+                self.in_flight.remove(trace);
+            }
+        } else if cache.tracked != Some(size) {
+            self.in_flight.update(trace, size);
+            cache.tracked = Some(size);
+        }
+    }
+
+    /// Decides the traces buffering spans for longer than the maximum trace age.
+    fn reap_expired(&self) {
+        let max_age = match self.max_trace_age {
+            Some(max_age) => max_age,
+            None => return,
+        };
+
+        for trace in self.in_flight.expired(max_age, Instant::now()) {
+            let mut trace_ext = trace.extensions_mut();
+            if let Some(cache) = trace_ext.get_mut::<TraceCache>() {
+                // The trace may have been tracked again before its lock was taken.
+                cache.tracked = None;
+                self.in_flight.remove(&trace);
+                // The trace may have been flushed since it expired.
+                if !cache.spans.is_empty() {
+                    let decision = self.sample(cache, true);
+                    self.decide_early(cache, &decision);
+                }
+            }
+        }
+    }
+
+    /// Evicts traces until the buffered span data fits the memory budget.
+    fn enforce_memory_budget(&self) {
+        let (limit, order, action) = match self.memory_budget {
+            Some((MemoryBudget::Spans(limit) | MemoryBudget::Bytes(limit), order, action)) => {
+                (limit, order, action)
+            }
+            None => return,
+        };
+
+        for trace in self.in_flight.evict(limit, order) {
+            self.evict(&trace, action);
+        }
+    }
+
+    /// Drops or decides an evicted trace according to `action`.
+    fn evict(&self, trace: &Trace, action: EvictionAction) {
+        let mut trace_ext = trace.extensions_mut();
+        let cache = match trace_ext.get_mut::<TraceCache>() {
+            Some(cache) => cache,
+            None => return,
+        };
+        // The trace may have been tracked again before its lock was taken, as it buffered
+        // another span.
+        cache.tracked = None;
+        self.in_flight.remove(trace);
+        // The trace may have been flushed since it was evicted.
+        if cache.spans.is_empty() {
+            return;
+        }
+
+        match action {
+            EvictionAction::Drop => {
+                cache.clear();
+                cache.decided = Some(false);
+            }
+            EvictionAction::Flush => {
+                let decision = self.sample(cache, true);
+                self.decide_early(cache, &decision);
+            }
+        }
+    }
+
+    /// Decides whether the buffered trace is exported. A `partial` trace lacks its root span.
+    fn sample(&self, cache: &mut TraceCache, partial: bool) -> Decision {
+        match &self.tail_sampler {
+            Some(sampler) => cache.sample(sampler.as_ref(), partial),
+            None => Decision::keep(),
+        }
+    }
+
+    /// Exports or releases the buffered spans of a trace according to `decision`. A `partial`
+    /// trace lacks its root span, which completes the labels or summary of the trace once it
+    /// closes.
+    fn flush(&self, cache: &mut TraceCache, decision: &Decision, partial: bool) {
+        if decision.record_trace() {
+            cache.record_decision(decision, partial);
+            if let Some(pruner) = &self.span_pruner {
+                pruner.prune(&mut cache.spans, partial);
+            }
+            cache.send_trace(&self.tracer);
+        } else if self.summarize_dropped && partial {
+            cache.start_summary();
+        } else if self.summarize_dropped {
+            cache.send_summary(&self.tracer);
+        } else {
+            cache.clear();
+        }
+    }
+
+    /// Exports or discards a span closing after its trace was decided, completing the labels or
+    /// the summary of a trace decided before its root closed. Spans closing after the root are
+    /// counted as late.
+    fn follow_decision(
+        &self,
+        trace: &Trace,
+        mut builder: otel::SpanBuilder,
+        parent_cx: &OtelContext,
+        is_root: bool,
+        record_trace: bool,
+    ) {
+        let mut trace_ext = trace.extensions_mut();
+        let mut cache = trace_ext.get_mut::<TraceCache>();
+        if let Some(cache) = cache.as_deref_mut() {
+            if cache.root_closed {
+                self.late_spans.record(record_trace);
+            }
+            cache.root_closed |= is_root;
+        }
+
+        if record_trace {
+            if let Some(cache) = cache {
+                cache.record_late_decision(&mut builder, is_root);
+            }
+            drop(trace_ext);
+            builder.start_with_context(&self.tracer, parent_cx);
+            return;
+        }
+
+        if let Some(summary) = cache.and_then(|cache| cache.summary.as_mut()) {
+            summary.add(&builder);
+            if is_root {
+                summary.record(&mut builder);
+                drop(trace_ext);
+                builder.start_with_context(&self.tracer, parent_cx);
+            }
+        }
+    }
+
+    fn reap_expired_in(dispatch: &tracing::Dispatch) {
+        let layer = dispatch
+            .downcast_ref::<OpenTelemetryLayer<S, T>>()
+            .expect("layer should downcast to expected type; this is a bug!");
+        layer.reap_expired();
+    }
+
     fn get_context(
         dispatch: &tracing::Dispatch,
         id: &span::Id,
@@ -495,6 +1116,8 @@ where
     /// [OpenTelemetry `Span`]: opentelemetry::trace::Span
     /// [tracing `Span`]: tracing::Span
     fn on_new_span(&self, attrs: &Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        self.reap_expired();
+
         let span = ctx.span(id).expect("Span not found, this is a bug");
         let mut extensions = span.extensions_mut();
 
@@ -502,6 +1125,15 @@ where
             extensions.insert(Timings::new());
         }
 
+        // Don't hold on to the spans of a decided trace until its next span closes. Spans of a
+        // dropped trace still record their status when the trace is summarized.
+        let dropped = match extensions.get_mut::<TraceContext>() {
+            Some(trace_context) => {
+                self.early_decision(&trace_context.trace) == Some(false) && !self.summarize_dropped
+            }
+            None => false,
+        };
+
         let parent_cx = self.parent_context(attrs, &ctx);
         let mut builder = self
             .tracer
@@ -515,6 +1147,13 @@ where
             builder.trace_id = Some(self.tracer.new_trace_id());
         }
 
+        // Spans of dropped traces only keep their ids, so that the trace is still propagated.
+        if dropped {
+            extensions.insert(DroppedSpan);
+            extensions.insert(OtelData { builder, parent_cx });
+            return;
+        }
+
         let builder_attrs = builder.attributes.get_or_insert(vec![]);
 
         let meta = attrs.metadata();
@@ -580,6 +1219,9 @@ where
     fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
         let span = ctx.span(id).expect("Span not found, this is a bug");
         let mut extensions = span.extensions_mut();
+        if extensions.get_mut::<DroppedSpan>().is_some() {
+            return;
+        }
         if let Some(data) = extensions.get_mut::<OtelData>() {
             values.record(&mut SpanAttributeVisitor(&mut data.builder));
         }
@@ -625,6 +1267,10 @@ where
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
         // Ignore events that are not in the context of a span
         if let Some(span) = ctx.lookup_current() {
+            if span.extensions().get::<DroppedSpan>().is_some() {
+                return;
+            }
+
             // Performing read operations before getting a write lock to avoid a deadlock
             // See https://github.com/tokio-rs/tracing/issues/763
             #[cfg(feature = "tracing-log")]
@@ -646,15 +1292,12 @@ where
 
             let mut extensions = span.extensions_mut();
             if let Some(OtelData { builder, .. }) = extensions.get_mut::<OtelData>() {
-                match builder.status {
-                    otel::Status::Unset => {
-                        if *meta.level() == tracing_core::Level::ERROR {
-                            builder.status = otel::Status::Error {
-                                description: "".into(),
-                            }
-                        }
+                if builder.status == otel::Status::Unset
+                    && *meta.level() == tracing_core::Level::ERROR
+                {
+                    builder.status = otel::Status::Error {
+                        description: "".into(),
                     }
-                    _ => {}
                 }
 
                 if let Some(ref mut events) = builder.events {
@@ -683,62 +1326,163 @@ where
                     let busy_ns = KeyValue::new("busy_ns", timings.busy);
                     let idle_ns = KeyValue::new("idle_ns", timings.idle);
 
-                    let attributes = builder.attributes.get_or_insert_with(|| Default::default());
+                    let attributes = builder.attributes.get_or_insert_with(Default::default);
                     attributes.push(KeyValue::new(busy_ns.key, busy_ns.value));
                     attributes.push(KeyValue::new(idle_ns.key, idle_ns.value));
                 }
             }
 
             // Assign end time
-            let builder = builder.with_end_time(SystemTime::now());
-
-            if let Some(trace_context) = extensions.get_mut::<TraceContext>() {
-                // If there's an active trace context, push the complete builder there so that tail
-                // sampling can be done.
-                let mut trace_ext = trace_context.trace.extensions_mut();
-                if trace_ext.get_mut::<TraceCache>().is_none() {
-                    trace_ext.insert(TraceCache::default());
+            let mut builder = builder.with_end_time(SystemTime::now());
+
+            let trace_context = match extensions.get_mut::<TraceContext>() {
+                Some(trace_context) => trace_context,
+                None => {
+                    // build and start span, drop span to export
+                    builder.start_with_context(&self.tracer, &parent_cx);
+                    return;
                 }
+            };
+            let is_root = trace_context.parent_id.is_none();
 
-                let record_trace = {
-                    let sample_decision = trace_ext.get_mut::<SampleDecision>();
-                    sample_decision.map(|d| d.record_trace).unwrap_or(true)
-                };
+            if is_root && truncated(&trace_context.trace) {
+                let attributes = builder.attributes.get_or_insert_with(Default::default);
+                attributes.push(KeyValue::new(TRACE_TRUNCATED, true));
+            }
 
-                let cache = trace_ext
-                    .get_mut::<TraceCache>()
-                    .expect("Cache not found, this is a bug");
+            // An explicit decision made by the application wins over the sampler, and takes
+            // effect as soon as it is made. Spans closing after a decision follow it.
+            if let Some(record_trace) = self.early_decision(&trace_context.trace) {
+                let trace = &trace_context.trace;
+                self.follow_decision(trace, builder, &parent_cx, is_root, record_trace);
+                return;
+            }
 
-                cache.spans.push_back(OtelData { builder, parent_cx });
+            // If there's an active trace context, push the complete builder there so that tail
+            // sampling can be done.
+            let mut trace_ext = trace_context.trace.extensions_mut();
+            if trace_ext.get_mut::<TraceCache>().is_none() {
+                trace_ext.insert(TraceCache::default());
+            }
 
-                // Now, if this is the top level span, see if we can flush.
-                if trace_context.parent_id.is_none() {
-                    if record_trace {
-                        cache.send_trace(&self.tracer);
-                    } else {
-                        cache.clear();
-                    }
+            let cache = trace_ext
+                .get_mut::<TraceCache>()
+                .expect("Cache not found, this is a bug");
+
+            // Another span of the trace may have decided it in the meantime.
+            if let Some(record_trace) = cache.decided {
+                drop(trace_ext);
+                let trace = &trace_context.trace;
+                self.follow_decision(trace, builder, &parent_cx, is_root, record_trace);
+                return;
+            }
+
+            let overflow = match self.span_cap {
+                Some((max_spans, overflow)) if !is_root && cache.spans.len() >= max_spans => {
+                    Some(overflow)
                 }
-            } else {
-                // build and start span, drop span to export
-                builder.start_with_context(&self.tracer, &parent_cx);
+                _ => None,
+            };
+
+            cache.truncated |= overflow.is_some();
+            match overflow {
+                Some(SpanOverflow::DropNewest) => return,
+                Some(SpanOverflow::DropOldest) => cache.pop_oldest(),
+                Some(SpanOverflow::Flush) | None => {}
             }
+
+            cache.push(BufferedSpan {
+                data: OtelData { builder, parent_cx },
+                name: span.name(),
+                target: span.metadata().target(),
+            });
+
+            if overflow == Some(SpanOverflow::Flush) {
+                let decision = self.sample(cache, true);
+                self.decide_early(cache, &decision);
+            } else if is_root {
+                // Now, if this is the top level span, flush.
+                let decision = self.sample(cache, false);
+                self.flush(cache, &decision, false);
+                cache.decided = Some(decision.record_trace());
+                cache.root_closed = true;
+            }
+
+            self.track(&trace_context.trace, cache);
+            drop(trace_ext);
+            self.enforce_memory_budget();
+            self.reap_expired();
         }
     }
 
-    // SAFETY: this is safe because the `WithContext` function pointer is valid
-    // for the lifetime of `&self`.
+    // SAFETY: this is safe because the `WithContext` and `ReapExpired` function
+    // pointers are valid for the lifetime of `&self`.
     unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
         match id {
             id if id == TypeId::of::<Self>() => Some(self as *const _ as *const ()),
             id if id == TypeId::of::<WithContext>() => {
                 Some(&self.get_context as *const _ as *const ())
             }
+            id if id == TypeId::of::<ReapExpired>() => {
+                Some(&self.reap_expired as *const _ as *const ())
+            }
             _ => None,
         }
     }
 }
 
+/// Whether `trace` hit the span cap.
+fn truncated(trace: &Trace) -> bool {
+    let trace_ext = trace.extensions();
+    matches!(
+        trace_ext.get::<TraceCache>(),
+        Some(TraceCache {
+            truncated: true,
+            ..
+        })
+    )
+}
+
+/// Aggregates of the spans of a dropped trace, recorded on its root span.
+#[derive(Default)]
+struct Summary {
+    span_count: usize,
+    error_count: usize,
+    start: Option<SystemTime>,
+    end: Option<SystemTime>,
+}
+
+impl Summary {
+    fn add(&mut self, builder: &otel::SpanBuilder) {
+        self.span_count += 1;
+        if matches!(builder.status, otel::Status::Error { .. }) {
+            self.error_count += 1;
+        }
+        self.start = match (self.start, builder.start_time) {
+            (Some(start), Some(other)) => Some(start.min(other)),
+            (start, other) => start.or(other),
+        };
+        self.end = match (self.end, builder.end_time) {
+            (Some(end), Some(other)) => Some(end.max(other)),
+            (end, other) => end.or(other),
+        };
+    }
+
+    fn record(&self, builder: &mut otel::SpanBuilder) {
+        let attributes = builder.attributes.get_or_insert_with(Vec::new);
+        attributes.push(KeyValue::new(SAMPLING_SUMMARY, true));
+        attributes.push(KeyValue::new(TRACE_SPAN_COUNT, self.span_count as i64));
+        attributes.push(KeyValue::new(TRACE_ERROR_COUNT, self.error_count as i64));
+        if let (Some(start), Some(end)) = (self.start, self.end) {
+            let duration = end.duration_since(start).unwrap_or_default();
+            attributes.push(KeyValue::new(TRACE_DURATION, duration.as_nanos() as i64));
+        }
+    }
+}
+
+/// Marks the spans opened after their trace was dropped, which record nothing.
+struct DroppedSpan;
+
 struct Timings {
     idle: i64,
     busy: i64,
@@ -767,8 +1511,9 @@ mod tests {
     use std::time::SystemTime;
     use tracing_subscriber::prelude::*;
 
-    #[derive(Debug, Clone)]
-    struct TestTracer(Arc<Mutex<Option<OtelData>>>);
+    /// Records the last exported span, and the names of all exported spans.
+    #[derive(Debug, Clone, Default)]
+    struct TestTracer(Arc<Mutex<Option<OtelData>>>, Arc<Mutex<Vec<String>>>);
     impl otel::Tracer for TestTracer {
         type Span = noop::NoopSpan;
         fn start_with_context<T>(&self, _name: T, _context: &OtelContext) -> Self::Span
@@ -788,6 +1533,7 @@ mod tests {
             builder: otel::SpanBuilder,
             parent_cx: &OtelContext,
         ) -> Self::Span {
+            self.1.lock().unwrap().push(builder.name.to_string());
             *self.0.lock().unwrap() = Some(OtelData {
                 builder,
                 parent_cx: parent_cx.clone(),
@@ -808,6 +1554,31 @@ mod tests {
         }
     }
 
+    #[derive(Clone, Default)]
+    struct TestSampler {
+        record_trace: bool,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TailSampler for TestSampler {
+        fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+            *self.seen.lock().unwrap() = trace
+                .spans()
+                .iter()
+                .map(|span| span.data.builder.name.to_string())
+                .collect();
+
+            if self.record_trace {
+                Decision::keep()
+                    .with_policy("test")
+                    .with_rule("test:rule")
+                    .with_probability(0.25)
+            } else {
+                Decision::drop()
+            }
+        }
+    }
+
     #[derive(Debug, Clone)]
     struct TestSpan(otel::SpanContext);
     impl otel::Span for TestSpan {
@@ -835,7 +1606,7 @@ mod tests {
     #[test]
     fn dynamic_span_names() {
         let dynamic_name = "GET http://example.com".to_string();
-        let tracer = TestTracer(Arc::new(Mutex::new(None)));
+        let tracer = TestTracer::default();
         let subscriber = tracing_subscriber::registry().with(layer().with_tracer(tracer.clone()));
 
         tracing::subscriber::with_default(subscriber, || {
@@ -854,7 +1625,7 @@ mod tests {
 
     #[test]
     fn span_kind() {
-        let tracer = TestTracer(Arc::new(Mutex::new(None)));
+        let tracer = TestTracer::default();
         let subscriber = tracing_subscriber::registry().with(layer().with_tracer(tracer.clone()));
 
         tracing::subscriber::with_default(subscriber, || {
@@ -876,7 +1647,7 @@ mod tests {
 
     #[test]
     fn span_status_code() {
-        let tracer = TestTracer(Arc::new(Mutex::new(None)));
+        let tracer = TestTracer::default();
         let subscriber = tracing_subscriber::registry().with(layer().with_tracer(tracer.clone()));
 
         tracing::subscriber::with_default(subscriber, || {
@@ -897,7 +1668,7 @@ mod tests {
 
     #[test]
     fn trace_id_from_existing_context() {
-        let tracer = TestTracer(Arc::new(Mutex::new(None)));
+        let tracer = TestTracer::default();
         let subscriber = tracing_subscriber::registry().with(layer().with_tracer(tracer.clone()));
         let trace_id = otel::TraceId::from(42u128);
         let existing_cx = OtelContext::current_with_span(TestSpan(otel::SpanContext::new(
@@ -928,7 +1699,7 @@ mod tests {
 
     #[test]
     fn includes_timings() {
-        let tracer = TestTracer(Arc::new(Mutex::new(None)));
+        let tracer = TestTracer::default();
         let subscriber = tracing_subscriber::registry().with(
             layer()
                 .with_tracer(tracer.clone())
@@ -960,7 +1731,7 @@ mod tests {
 
     #[test]
     fn records_error_fields() {
-        let tracer = TestTracer(Arc::new(Mutex::new(None)));
+        let tracer = TestTracer::default();
         let subscriber = tracing_subscriber::registry().with(layer().with_tracer(tracer.clone()));
 
         use std::error::Error;
@@ -1030,4 +1801,634 @@ mod tests {
             ))),
         );
     }
+
+    #[test]
+    fn tail_sampler_sees_complete_trace() {
+        let tracer = TestTracer::default();
+        let sampler = TestSampler {
+            record_trace: true,
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_tail_sampler(sampler.clone()),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug_span!("root").in_scope(|| {
+                tracing::debug_span!("child").in_scope(|| {});
+            });
+        });
+
+        assert_eq!(*sampler.seen.lock().unwrap(), vec!["child", "root"]);
+
+        let root = tracer.0.lock().unwrap().take().unwrap();
+        let attributes = root.builder.attributes.unwrap();
+        assert_eq!(root.builder.name, "root");
+        assert!(attributes.contains(&KeyValue::new(SAMPLING_POLICY, "test")));
+        assert!(attributes.contains(&KeyValue::new(SAMPLING_RULE, "test:rule")));
+        assert!(attributes.contains(&KeyValue::new(SAMPLING_ADJUSTED_COUNT, 4.0)));
+    }
+
+    #[test]
+    fn tail_sampler_drops_trace() {
+        let tracer = TestTracer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_tail_sampler(TestSampler::default()),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug_span!("root").in_scope(|| {
+                tracing::debug_span!("child").in_scope(|| {});
+            });
+        });
+
+        assert!(tracer.0.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn dropped_trace_summary() {
+        let tracer = TestTracer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_tail_sampler(TestSampler::default())
+                    .with_dropped_trace_summaries(true),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug_span!("root").in_scope(|| {
+                tracing::debug_span!("child").in_scope(|| tracing::error!("failed"));
+                tracing::debug_span!("child").in_scope(|| {});
+            });
+        });
+
+        let root = tracer.0.lock().unwrap().take().unwrap();
+        let attributes = root.builder.attributes.unwrap();
+        let find = |key: &str| {
+            attributes
+                .iter()
+                .find(|attribute| attribute.key.as_str() == key)
+                .map(|attribute| attribute.value.clone())
+        };
+        assert_eq!(root.builder.name, "root");
+        assert_eq!(find(SAMPLING_SUMMARY), Some(Value::Bool(true)));
+        assert_eq!(find(TRACE_SPAN_COUNT), Some(Value::I64(3)));
+        assert_eq!(find(TRACE_ERROR_COUNT), Some(Value::I64(1)));
+        assert!(find(TRACE_DURATION).is_some());
+    }
+
+    #[test]
+    fn span_cap() {
+        let run = |overflow| {
+            let tracer = TestTracer::default();
+            let sampler = TestSampler {
+                record_trace: true,
+                ..Default::default()
+            };
+            let subscriber = tracing_subscriber::registry()
+                .with(crate::TraceContextLayer::default())
+                .with(
+                    layer()
+                        .with_tracer(tracer.clone())
+                        .with_tail_sampler(sampler.clone())
+                        .with_max_spans_per_trace(2, overflow),
+                );
+
+            let mut seen_before_root = Vec::new();
+            tracing::subscriber::with_default(subscriber, || {
+                tracing::debug_span!("root").in_scope(|| {
+                    for name in ["a", "b", "c"] {
+                        tracing::debug_span!("child", otel.name = name).in_scope(|| {});
+                    }
+                    seen_before_root = sampler.seen.lock().unwrap().clone();
+                });
+            });
+
+            let root = tracer.0.lock().unwrap().take().unwrap();
+            let truncated = root
+                .builder
+                .attributes
+                .unwrap()
+                .contains(&KeyValue::new(TRACE_TRUNCATED, true));
+            let seen = sampler.seen.lock().unwrap().clone();
+            (truncated, seen_before_root, seen)
+        };
+
+        let (truncated, _, seen) = run(SpanOverflow::DropNewest);
+        assert!(truncated);
+        assert_eq!(seen, ["a", "b", "root"]);
+
+        let (truncated, _, seen) = run(SpanOverflow::DropOldest);
+        assert!(truncated);
+        assert_eq!(seen, ["b", "c", "root"]);
+
+        // The trace is decided from the first spans, and the root streamed. No span is lost.
+        let (truncated, seen_before_root, seen) = run(SpanOverflow::Flush);
+        assert!(truncated);
+        assert_eq!(seen_before_root, ["a", "b", "c"]);
+        assert_eq!(seen, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn evicted_trace_is_flushed() {
+        let tracer = TestTracer::default();
+        let sampler = TestSampler {
+            record_trace: true,
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_tail_sampler(sampler.clone())
+                    .with_span_pruner(SpanPruner::new().shorter_than(Duration::from_millis(10)))
+                    .with_memory_budget(
+                        MemoryBudget::Spans(1),
+                        EvictionOrder::OldestFirst,
+                        EvictionAction::Flush,
+                    ),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let root = tracing::debug_span!("root");
+            tracing::debug_span!(parent: &root, "kept")
+                .in_scope(|| std::thread::sleep(Duration::from_millis(20)));
+            // The last buffered span of a partial trace is not its root, and is pruned as well.
+            tracing::debug_span!(parent: &root, "pruned").in_scope(|| {});
+            assert_eq!(*sampler.seen.lock().unwrap(), vec!["kept", "pruned"]);
+            assert_eq!(*tracer.1.lock().unwrap(), vec!["kept"]);
+
+            let kept = tracer.0.lock().unwrap().take().unwrap();
+            let attributes = kept.builder.attributes.unwrap();
+            assert!(!attributes.contains(&KeyValue::new(SAMPLING_POLICY, "test")));
+            assert!(attributes.contains(&KeyValue::new(SAMPLING_ADJUSTED_COUNT, 4.0)));
+
+            // The root is labelled once it closes.
+            drop(root);
+            let root = tracer.0.lock().unwrap().take().unwrap();
+            let attributes = root.builder.attributes.unwrap();
+            assert_eq!(root.builder.name, "root");
+            assert!(attributes.contains(&KeyValue::new(SAMPLING_POLICY, "test")));
+            assert!(attributes.contains(&KeyValue::new(SAMPLING_ADJUSTED_COUNT, 4.0)));
+        });
+    }
+
+    #[test]
+    fn early_dropped_trace_summary() {
+        let tracer = TestTracer::default();
+        let sampler = TestSampler::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_tail_sampler(sampler.clone())
+                    .with_dropped_trace_summaries(true)
+                    .with_max_spans_per_trace(1, SpanOverflow::Flush),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::debug_span!("root").in_scope(|| {
+                for name in ["a", "b", "c"] {
+                    tracing::debug_span!("child", otel.name = name).in_scope(|| {});
+                }
+                assert_eq!(*sampler.seen.lock().unwrap(), vec!["a", "b"]);
+                assert!(tracer.1.lock().unwrap().is_empty());
+            });
+        });
+
+        // Only the root is exported, summarizing all spans.
+        assert_eq!(*tracer.1.lock().unwrap(), vec!["root"]);
+        let root = tracer.0.lock().unwrap().take().unwrap();
+        let attributes = root.builder.attributes.unwrap();
+        assert!(attributes.contains(&KeyValue::new(SAMPLING_SUMMARY, true)));
+        assert!(attributes.contains(&KeyValue::new(TRACE_SPAN_COUNT, 4)));
+        assert!(attributes.contains(&KeyValue::new(TRACE_TRUNCATED, true)));
+    }
+
+    #[test]
+    fn app_dropped_trace_summary() {
+        let tracer = TestTracer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_dropped_trace_summaries(true),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let root = tracing::debug_span!("root");
+            tracing::debug_span!(parent: &root, "buffered").in_scope(|| {});
+            decide(&root, false);
+            tracing::debug_span!(parent: &root, "failed").in_scope(|| tracing::error!("boom"));
+            assert!(tracer.1.lock().unwrap().is_empty());
+            drop(root);
+        });
+
+        // Only the root is exported, summarizing the spans before and after the decision.
+        assert_eq!(*tracer.1.lock().unwrap(), vec!["root"]);
+        let root = tracer.0.lock().unwrap().take().unwrap();
+        let attributes = root.builder.attributes.unwrap();
+        assert!(attributes.contains(&KeyValue::new(SAMPLING_SUMMARY, true)));
+        assert!(attributes.contains(&KeyValue::new(TRACE_SPAN_COUNT, 3)));
+        assert!(attributes.contains(&KeyValue::new(TRACE_ERROR_COUNT, 1)));
+    }
+
+    #[test]
+    fn memory_budget_evicts_traces() {
+        let tracer = TestTracer::default();
+        let sampler = TestSampler {
+            record_trace: true,
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_tail_sampler(sampler.clone())
+                    .with_memory_budget(
+                        MemoryBudget::Spans(2),
+                        EvictionOrder::OldestFirst,
+                        EvictionAction::Drop,
+                    ),
+            );
+
+        let exported = || {
+            let data = tracer.0.lock().unwrap().take();
+            data.map(|data| data.builder.name.to_string())
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            let old = tracing::debug_span!(parent: None, "old");
+            tracing::debug_span!(parent: &old, "a").in_scope(|| {});
+
+            // Buffering a third span evicts the oldest trace.
+            let new = tracing::debug_span!(parent: None, "new");
+            tracing::debug_span!(parent: &new, "b").in_scope(|| {});
+            assert_eq!(buffered(&old), 1);
+            tracing::debug_span!(parent: &new, "c").in_scope(|| {});
+            assert_eq!(buffered(&old), 0);
+
+            drop(new);
+            assert_eq!(exported().as_deref(), Some("new"));
+            assert_eq!(*sampler.seen.lock().unwrap(), vec!["b", "c", "new"]);
+
+            // Later spans of the evicted trace are dropped.
+            tracing::debug_span!(parent: &old, "d").in_scope(|| {});
+            assert_eq!(buffered(&old), 0);
+            drop(old);
+            assert_eq!(exported(), None);
+        });
+    }
+
+    #[test]
+    fn evicted_trace_is_untracked() {
+        let layer = layer::<tracing_subscriber::Registry>().with_memory_budget(
+            MemoryBudget::Spans(1),
+            EvictionOrder::OldestFirst,
+            EvictionAction::Drop,
+        );
+        let trace = Trace::new();
+        let mut cache = TraceCache::default();
+        cache.push(sampler::tests::span("a"));
+        cache.push(sampler::tests::span("b"));
+        layer.track(&trace, &mut cache);
+        trace.extensions_mut().insert(cache);
+
+        let evicted = layer.in_flight.evict(1, EvictionOrder::OldestFirst);
+        assert_eq!(layer.in_flight.total(), 0);
+
+        // Another span of the trace closes before the evicted trace is locked.
+        {
+            let mut trace_ext = trace.extensions_mut();
+            let cache = trace_ext.get_mut::<TraceCache>().unwrap();
+            cache.push(sampler::tests::span("c"));
+            layer.track(&trace, cache);
+        }
+        assert_eq!(layer.in_flight.total(), 3);
+
+        layer.evict(&evicted[0], EvictionAction::Drop);
+        assert_eq!(layer.in_flight.total(), 0);
+    }
+
+    #[test]
+    fn max_trace_age_decides_trace() {
+        let tracer = TestTracer::default();
+        let sampler = TestSampler {
+            record_trace: true,
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_tail_sampler(sampler.clone())
+                    .with_max_trace_age(Duration::from_millis(1)),
+            );
+
+        let exported = || {
+            let data = tracer.0.lock().unwrap().take();
+            data.map(|data| data.builder.name.to_string())
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            let root = tracing::debug_span!("root");
+            tracing::debug_span!(parent: &root, "a").in_scope(|| {});
+            assert_eq!(buffered(&root), 1);
+            assert_eq!(exported(), None);
+
+            // The next span finds the trace expired, and decides it.
+            std::thread::sleep(Duration::from_millis(5));
+            let b = tracing::debug_span!(parent: &root, "b");
+            assert_eq!(buffered(&root), 0);
+            assert_eq!(exported().as_deref(), Some("a"));
+            assert_eq!(*sampler.seen.lock().unwrap(), vec!["a"]);
+
+            // Later spans follow the decision.
+            drop(b);
+            assert_eq!(exported().as_deref(), Some("b"));
+            drop(root);
+            assert_eq!(exported().as_deref(), Some("root"));
+            assert_eq!(*sampler.seen.lock().unwrap(), vec!["a"]);
+        });
+    }
+
+    #[test]
+    fn reaps_expired_traces_without_spans() {
+        let tracer = TestTracer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_max_trace_age(Duration::from_millis(1)),
+            );
+        let dispatch = tracing::Dispatch::new(subscriber);
+
+        tracing::dispatcher::with_default(&dispatch, || {
+            let root = tracing::debug_span!("root");
+            tracing::debug_span!(parent: &root, "a").in_scope(|| {});
+            assert_eq!(buffered(&root), 1);
+
+            // No span opens or closes once the trace expired.
+            std::thread::sleep(Duration::from_millis(5));
+            reap_expired_traces(&dispatch);
+            assert_eq!(buffered(&root), 0);
+            assert_eq!(*tracer.1.lock().unwrap(), vec!["a"]);
+        });
+    }
+
+    #[test]
+    fn late_spans_follow_decision() {
+        let tracer = TestTracer::default();
+        let sampler = TestSampler {
+            record_trace: true,
+            ..Default::default()
+        };
+        let layer = layer()
+            .with_tracer(tracer.clone())
+            .with_tail_sampler(sampler.clone());
+        let late_spans = layer.late_spans();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(layer);
+
+        let exported = || {
+            let data = tracer.0.lock().unwrap().take();
+            data.map(|data| data.builder.name.to_string())
+        };
+
+        // The registry keeps parents open until their children close, so late spans are set up
+        // by hand.
+        tracing::subscriber::with_default(subscriber, || {
+            let root = tracing::debug_span!("root");
+            let late = tracing::debug_span!(parent: None, "late");
+            adopt(&late, &root);
+            drop(root);
+            assert_eq!(exported().as_deref(), Some("root"));
+
+            drop(late);
+            assert_eq!(exported().as_deref(), Some("late"));
+            assert_eq!(*sampler.seen.lock().unwrap(), vec!["root"]);
+            assert_eq!(late_spans.exported(), 1);
+
+            let root = tracing::debug_span!("root");
+            decide(&root, false);
+            let late = tracing::debug_span!(parent: None, "late");
+            adopt(&late, &root);
+            drop(root);
+            drop(late);
+            assert_eq!(exported(), None);
+            assert_eq!(late_spans.dropped(), 1);
+        });
+    }
+
+    #[test]
+    fn early_decided_spans_are_not_late() {
+        let tracer = TestTracer::default();
+        let layer = layer().with_tracer(tracer.clone());
+        let late_spans = layer.late_spans();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(layer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let root = tracing::debug_span!("root");
+            tracing::debug_span!(parent: &root, "buffered").in_scope(|| {});
+            decide(&root, true);
+            tracing::debug_span!(parent: &root, "streamed").in_scope(|| {});
+            drop(root);
+        });
+
+        assert_eq!(
+            *tracer.1.lock().unwrap(),
+            vec!["buffered", "streamed", "root"]
+        );
+        assert_eq!(late_spans.exported(), 0);
+    }
+
+    #[test]
+    fn sample_decision_overrides_tail_sampler() {
+        let tracer = TestTracer::default();
+        let sampler = TestSampler::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_tail_sampler(sampler.clone()),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            decide(&tracing::debug_span!("root"), true);
+        });
+
+        assert!(sampler.seen.lock().unwrap().is_empty());
+        assert!(tracer.0.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn early_keep_streams_trace() {
+        let tracer = TestTracer::default();
+        let sampler = TestSampler::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_tail_sampler(sampler.clone()),
+            );
+
+        let exported = || {
+            let data = tracer.0.lock().unwrap().take();
+            data.map(|data| data.builder.name.to_string())
+        };
+
+        tracing::subscriber::with_default(subscriber, || {
+            let root = tracing::debug_span!("root");
+            root.in_scope(|| {
+                tracing::debug_span!("buffered").in_scope(|| {});
+                assert_eq!(exported(), None);
+
+                // The buffered span is flushed as soon as the next span opens.
+                decide(&root, true);
+                let streamed = tracing::debug_span!("streamed");
+                assert_eq!(exported().as_deref(), Some("buffered"));
+
+                drop(streamed);
+                assert_eq!(exported().as_deref(), Some("streamed"));
+            });
+        });
+
+        assert!(sampler.seen.lock().unwrap().is_empty());
+        assert_eq!(exported().as_deref(), Some("root"));
+    }
+
+    #[test]
+    fn early_keep_prunes_trace() {
+        let tracer = TestTracer::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_span_pruner(SpanPruner::new().shorter_than(Duration::from_millis(10))),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let root = tracing::debug_span!("root");
+            tracing::debug_span!(parent: &root, "slow")
+                .in_scope(|| std::thread::sleep(Duration::from_millis(20)));
+            tracing::debug_span!(parent: &root, "fast").in_scope(|| {});
+
+            decide(&root, true);
+            tracing::debug_span!(parent: &root, "streamed");
+            assert_eq!(*tracer.1.lock().unwrap(), vec!["slow", "streamed"]);
+        });
+    }
+
+    #[test]
+    fn early_drop_stops_buffering() {
+        let tracer = TestTracer::default();
+        let sampler = TestSampler {
+            record_trace: true,
+            ..Default::default()
+        };
+        let subscriber = tracing_subscriber::registry()
+            .with(crate::TraceContextLayer::default())
+            .with(
+                layer()
+                    .with_tracer(tracer.clone())
+                    .with_tail_sampler(sampler.clone()),
+            );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let root = tracing::debug_span!("root");
+            root.in_scope(|| {
+                tracing::debug_span!("buffered").in_scope(|| {});
+                assert_eq!(buffered(&root), 1);
+
+                // The buffered span is released as soon as the next span opens.
+                decide(&root, false);
+                let skipped = tracing::debug_span!("skipped", field = 42);
+                assert_eq!(buffered(&root), 0);
+
+                skipped.in_scope(|| tracing::error!("recorded nowhere"));
+                skipped.with_subscriber(|(id, dispatch)| {
+                    let registry = dispatch.downcast_ref::<tracing_subscriber::Registry>();
+                    let span = registry.unwrap().span(id).unwrap();
+                    let extensions = span.extensions();
+                    let builder = &extensions.get::<OtelData>().unwrap().builder;
+                    assert!(builder.attributes.is_none());
+                    assert!(builder.events.is_none());
+                    assert!(builder.span_id.is_some());
+                });
+
+                drop(skipped);
+                assert_eq!(buffered(&root), 0);
+            });
+        });
+
+        assert!(sampler.seen.lock().unwrap().is_empty());
+        assert!(tracer.0.lock().unwrap().is_none());
+    }
+
+    /// The number of spans buffered for the trace of `span`.
+    fn buffered(span: &tracing::Span) -> usize {
+        let mut count = 0;
+        span.with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<tracing_subscriber::Registry>();
+            let span = registry.unwrap().span(id).unwrap();
+            let extensions = span.extensions();
+            let trace_context = extensions.get::<TraceContext>().unwrap();
+            let trace_ext = trace_context.trace.extensions();
+            count = trace_ext
+                .get::<TraceCache>()
+                .map_or(0, |cache| cache.spans.len());
+        });
+        count
+    }
+
+    /// Moves `span` into the trace of `parent`, as a child which may outlive it.
+    fn adopt(span: &tracing::Span, parent: &tracing::Span) {
+        let mut trace_context = None;
+        parent.with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<tracing_subscriber::Registry>();
+            let parent = registry.unwrap().span(id).unwrap();
+            trace_context = parent.extensions().get::<TraceContext>().map(|p| p.child());
+        });
+        span.with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<tracing_subscriber::Registry>();
+            let span = registry.unwrap().span(id).unwrap();
+            span.extensions_mut().replace(trace_context.unwrap());
+        });
+    }
+
+    /// Makes the application level sampling decision for the trace of `span`.
+    fn decide(span: &tracing::Span, record_trace: bool) {
+        span.with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<tracing_subscriber::Registry>();
+            let span = registry.unwrap().span(id).unwrap();
+            let extensions = span.extensions();
+            let trace_context = extensions.get::<TraceContext>().unwrap();
+            trace_context
+                .trace
+                .extensions_mut()
+                .insert(SampleDecision { record_trace });
+        });
+    }
 }
@@ -48,8 +48,8 @@
 //! special fields are:
 //!
 //! * `otel.name`: Override the span name sent to OpenTelemetry exporters.
-//! Setting this field is useful if you want to display non-static information
-//! in your span name.
+//!   Setting this field is useful if you want to display non-static information
+//!   in your span name.
 //! * `otel.kind`: Set the span kind to one of the supported OpenTelemetry [span kinds].
 //! * `otel.status_code`: Set the span status code to one of the supported OpenTelemetry [span status codes].
 //! * `otel.status_message`: Set the span status message.
@@ -127,14 +127,20 @@
 #![cfg_attr(test, deny(warnings))]
 #![cfg_attr(docsrs, deny(rustdoc::broken_intra_doc_links))]
 
+/// Bookkeeping of the traces buffered in memory.
+mod in_flight;
 /// Implementation of the trace::Layer as a source of OpenTelemetry data.
 mod layer;
+/// Tail sampling decisions over buffered traces.
+pub mod sampler;
 /// Span extension which enables OpenTelemetry context management.
 mod span_ext;
 /// Protocols for OpenTelemetry Tracers that are compatible with Tracing
 mod tracer;
 
-pub use layer::{layer, OpenTelemetryLayer};
+pub use in_flight::{EvictionAction, EvictionOrder, MemoryBudget};
+pub use layer::{layer, reap_expired_traces, LateSpans, OpenTelemetryLayer, SpanOverflow};
+pub use sampler::TailSampler;
 pub use span_ext::OpenTelemetrySpanExt;
 pub use tracer::PreSampledTracer;
 
@@ -0,0 +1,313 @@
+use crate::opentelemetry::sampler::probabilistic::sample_trace_id;
+use crate::opentelemetry::sampler::{name_in, BufferedTrace, Decision, TailSampler};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Windows without traffic after which a root span name is forgotten.
+const IDLE_WINDOWS: u32 = 10;
+
+/// Keeps a target number of traces per minute for each root span name.
+///
+/// The sampler tracks how many traces arrive for each root span name, and adjusts the ratio of
+/// traces it keeps every minute so that kept traces converge on the target rate. Low traffic
+/// endpoints are thus kept in full while hot endpoints are capped. Within a minute, the
+/// probability of keeping a trace also drops once more traces than expected arrived for a name,
+/// which bounds bursts and names seen for the first time to about the target. Kept traces record
+/// the probability they were kept with. Partial traces, whose root span is still open, are
+/// dropped.
+///
+/// Like the [`LatencySampler`] and the [`RouteSampler`], root spans are named by their
+/// `otel.name`, unless only the name of the `tracing` span is tracked already.
+///
+/// Like the [`TraceIdRatioSampler`], decisions are derived from the trace id.
+///
+/// [`LatencySampler`]: crate::opentelemetry::sampler::LatencySampler
+/// [`RouteSampler`]: crate::opentelemetry::sampler::RouteSampler
+/// [`TraceIdRatioSampler`]: crate::opentelemetry::sampler::TraceIdRatioSampler
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::AdaptiveSampler;
+/// use onesignal_tracing_tail_sample::TraceContextLayer;
+/// use std::sync::Arc;
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// let sampler = Arc::new(AdaptiveSampler::new(60.0));
+/// let telemetry = onesignal_tracing_tail_sample::opentelemetry::layer()
+///     .with_tail_sampler(sampler.clone());
+/// let subscriber = Registry::default()
+///     .with(TraceContextLayer::default())
+///     .with(telemetry);
+/// # drop(subscriber);
+///
+/// // Later, e.g. when reporting metrics.
+/// for (name, ratio) in sampler.ratios() {
+///     println!("{}: keeping {:.2}%", name, ratio * 100.0);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct AdaptiveSampler {
+    target_per_minute: f64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    names: HashMap<String, NameState>,
+    last_pruned: Instant,
+}
+
+#[derive(Debug)]
+struct NameState {
+    window_start: Instant,
+    seen: u64,
+    kept: u64,
+    /// Smoothed number of traces seen per window.
+    rate: Option<f64>,
+    ratio: f64,
+}
+
+impl NameState {
+    fn new(now: Instant) -> Self {
+        NameState {
+            window_start: now,
+            seen: 0,
+            kept: 0,
+            rate: None,
+            ratio: 1.0,
+        }
+    }
+
+    fn roll(&mut self, now: Instant, target: f64) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if elapsed < WINDOW {
+            return;
+        }
+
+        let observed = self.seen as f64 * WINDOW.as_secs_f64() / elapsed.as_secs_f64();
+        let rate = match self.rate {
+            Some(rate) => (rate + observed) / 2.0,
+            None => observed,
+        };
+
+        self.rate = Some(rate);
+        self.ratio = if rate > target { target / rate } else { 1.0 };
+        self.window_start = now;
+        self.seen = 0;
+        self.kept = 0;
+    }
+
+    /// The probability with which the trace numbered `seen` in the current window is kept.
+    ///
+    /// Past the traces expected in a window, e.g. in the first window of a name, what is left of
+    /// the target is spread over the traces seen so far. Every trace keeps a chance to be kept, so
+    /// that the probabilities recorded on kept traces stay accurate.
+    fn probability(&self, seen: u64, target: f64) -> f64 {
+        if seen as f64 <= self.rate.unwrap_or(0.0).max(target) {
+            return self.ratio;
+        }
+
+        let left = (target - self.kept as f64).max(target.min(1.0));
+        self.ratio.min(left / seen as f64)
+    }
+}
+
+impl AdaptiveSampler {
+    /// Create a sampler keeping about `target_per_minute` traces per minute for each root span
+    /// name.
+    pub fn new(target_per_minute: f64) -> Self {
+        AdaptiveSampler {
+            target_per_minute: target_per_minute.max(0.0),
+            state: Mutex::new(State {
+                names: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// The probability with which the next trace of root spans named `name` is kept.
+    pub fn ratio(&self, name: &str) -> Option<f64> {
+        let state = self.state.lock().expect("Mutex poisoned");
+        let target = self.target_per_minute;
+        state
+            .names
+            .get(name)
+            .map(|name| name.probability(name.seen + 1, target))
+    }
+
+    /// The probability with which the next trace of each root span name is kept.
+    pub fn ratios(&self) -> HashMap<String, f64> {
+        let state = self.state.lock().expect("Mutex poisoned");
+        let target = self.target_per_minute;
+        state
+            .names
+            .iter()
+            .map(|(name, state)| (name.clone(), state.probability(state.seen + 1, target)))
+            .collect()
+    }
+
+    fn sample_at(&self, trace: &BufferedTrace<'_>, now: Instant) -> Decision {
+        let root = match trace.root() {
+            Some(root) => root,
+            None => return Decision::drop(),
+        };
+
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        if now.saturating_duration_since(state.last_pruned) >= WINDOW {
+            state.last_pruned = now;
+            state.names.retain(|_, name| {
+                now.saturating_duration_since(name.window_start) < WINDOW * IDLE_WINDOWS
+            });
+        }
+
+        let name = name_in(&state.names, root);
+        let name = match state.names.get_mut(name) {
+            Some(name) => name,
+            None => state
+                .names
+                .entry(name.to_string())
+                .or_insert_with(|| NameState::new(now)),
+        };
+
+        name.roll(now, self.target_per_minute);
+        name.seen += 1;
+
+        let probability = name.probability(name.seen, self.target_per_minute);
+        if sample_trace_id(trace.trace_id(), probability) {
+            name.kept += 1;
+            Decision::keep()
+                .with_policy("adaptive")
+                .with_probability(probability)
+        } else {
+            Decision::drop()
+        }
+    }
+}
+
+impl TailSampler for AdaptiveSampler {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        self.sample_at(trace, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use crate::opentelemetry::sampler::BufferedSpan;
+    use opentelemetry::trace::TraceId;
+
+    fn root(name: &'static str, id: u64) -> Vec<BufferedSpan> {
+        let mut root = span(name);
+        let trace_id = id.wrapping_mul(0x9E37_79B9_7F4A_7C15) as u128;
+        root.data.builder.trace_id = Some(TraceId::from(trace_id));
+        vec![root]
+    }
+
+    fn sample(
+        sampler: &AdaptiveSampler,
+        name: &'static str,
+        traces: u64,
+        now: Instant,
+    ) -> Vec<Decision> {
+        (0..traces)
+            .map(|id| {
+                let spans = root(name, id);
+                sampler.sample_at(&BufferedTrace::new(&spans), now)
+            })
+            .filter(Decision::record_trace)
+            .collect()
+    }
+
+    fn kept(sampler: &AdaptiveSampler, name: &'static str, traces: u64, now: Instant) -> usize {
+        sample(sampler, name, traces, now).len()
+    }
+
+    #[test]
+    fn caps_new_names() {
+        let sampler = AdaptiveSampler::new(10.0);
+        let now = Instant::now();
+
+        let hot = sample(&sampler, "hot", 1000, now);
+        assert!((10..=20).contains(&hot.len()), "kept {}", hot.len());
+        let estimate: f64 = hot.iter().map(|kept| 1.0 / kept.probability()).sum();
+        assert!(
+            (500.0..=2000.0).contains(&estimate),
+            "estimated {}",
+            estimate
+        );
+
+        assert_eq!(kept(&sampler, "cold", 5, now), 5);
+        assert_eq!(sampler.ratio("cold"), Some(1.0));
+        assert!(sampler.ratio("hot").unwrap() <= 0.001);
+    }
+
+    #[test]
+    fn records_probability() {
+        let sampler = AdaptiveSampler::new(10.0);
+        let start = Instant::now();
+
+        kept(&sampler, "hot", 1000, start);
+        let hot = sample(&sampler, "hot", 1000, start + WINDOW);
+        assert!(!hot.is_empty());
+        assert!(hot.iter().all(|kept| kept.adjusted_count() == Some(100.0)));
+    }
+
+    #[test]
+    fn groups_by_name() {
+        let sampler = AdaptiveSampler::new(10.0);
+        let now = Instant::now();
+
+        kept(&sampler, "request", 1, now);
+        let mut spans = root("request", 1);
+        spans[0].data.builder.name = "GET /users".into();
+        sampler.sample_at(&BufferedTrace::new(&spans), now);
+        assert_eq!(sampler.ratios().len(), 1);
+
+        kept(&sampler, "GET /users", 1, now);
+        assert_eq!(sampler.ratios().len(), 2);
+    }
+
+    #[test]
+    fn converges_on_target() {
+        let sampler = AdaptiveSampler::new(10.0);
+        let start = Instant::now();
+
+        kept(&sampler, "hot", 1000, start);
+        kept(&sampler, "cold", 5, start);
+
+        let next = start + WINDOW;
+        let hot = kept(&sampler, "hot", 900, next);
+        let cold = kept(&sampler, "cold", 5, next);
+
+        assert_eq!(sampler.ratio("hot"), Some(0.01));
+        assert_eq!(sampler.ratio("cold"), Some(1.0));
+        assert!((3..=10).contains(&hot), "kept {}", hot);
+        assert_eq!(cold, 5);
+
+        let ratios = sampler.ratios();
+        assert_eq!(ratios.len(), 2);
+        assert_eq!(ratios["hot"], 0.01);
+
+        // Past the expected traces, what is left of the target is spread over later traces.
+        kept(&sampler, "hot", 200, next);
+        assert!(sampler.ratio("hot").unwrap() < 0.01);
+    }
+
+    #[test]
+    fn forgets_idle_names() {
+        let sampler = AdaptiveSampler::new(10.0);
+        let start = Instant::now();
+
+        kept(&sampler, "gone", 1, start);
+        kept(&sampler, "active", 1, start + WINDOW * IDLE_WINDOWS);
+
+        assert_eq!(sampler.ratio("gone"), None);
+        assert!(sampler.ratio("active").is_some());
+    }
+}
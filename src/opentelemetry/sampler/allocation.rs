@@ -0,0 +1,188 @@
+use crate::opentelemetry::sampler::{BufferedTrace, Decision, TailSampler};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Splits a global spans-per-second budget between several samplers.
+///
+/// This mirrors the `composite` policy of the OpenTelemetry Collector's tail sampling processor.
+/// Each sampler is allocated a percentage of the budget. Samplers are evaluated in order, and a
+/// trace is kept by the first sampler that keeps it while still having room in its allocation.
+/// Budget left unused by earlier samplers flows to later ones within the same second.
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::{
+///     ErrorSampler, LatencySampler, RateAllocationSampler, TraceIdRatioSampler,
+/// };
+/// use std::time::Duration;
+///
+/// let sampler = RateAllocationSampler::new(1000.0)
+///     .with_allocation(ErrorSampler, 50.0)
+///     .with_allocation(LatencySampler::new(Duration::from_secs(1)), 30.0)
+///     .with_allocation(TraceIdRatioSampler::new(0.01), 20.0);
+/// # drop(sampler);
+/// ```
+pub struct RateAllocationSampler {
+    max_spans_per_second: f64,
+    policies: Vec<(Box<dyn TailSampler>, f64)>,
+    window: Mutex<Window>,
+}
+
+struct Window {
+    start: Instant,
+    used: Vec<f64>,
+}
+
+impl RateAllocationSampler {
+    /// Create a sampler keeping at most `max_spans_per_second` spans, across all allocations.
+    pub fn new(max_spans_per_second: f64) -> Self {
+        RateAllocationSampler {
+            max_spans_per_second: max_spans_per_second.max(0.0),
+            policies: Vec::new(),
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                used: Vec::new(),
+            }),
+        }
+    }
+
+    /// Allocates `percent` of the budget to traces kept by `sampler`, after the samplers
+    /// allocated before it.
+    pub fn with_allocation<P>(mut self, sampler: P, percent: f64) -> Self
+    where
+        P: TailSampler + 'static,
+    {
+        let spans = self.max_spans_per_second * percent.clamp(0.0, 100.0) / 100.0;
+        self.policies.push((Box::new(sampler), spans));
+        self.window
+            .get_mut()
+            .expect("Mutex poisoned")
+            .used
+            .push(0.0);
+        self
+    }
+
+    fn sample_at(&self, trace: &BufferedTrace<'_>, now: Instant) -> Decision {
+        let spans = trace.spans().len() as f64;
+        let mut allocated = 0.0;
+
+        for (index, (sampler, allocation)) in self.policies.iter().enumerate() {
+            allocated += allocation;
+
+            let decision = sampler.sample(trace);
+            if !decision.record_trace() {
+                continue;
+            }
+
+            let mut window = self.window.lock().expect("Mutex poisoned");
+            if now.saturating_duration_since(window.start) >= Duration::from_secs(1) {
+                window.start = now;
+                window.used.iter_mut().for_each(|used| *used = 0.0);
+            }
+
+            // Unused budget of earlier allocations is available to this one.
+            let used = window.used[..=index].iter().sum::<f64>();
+            let total = window.used.iter().sum::<f64>();
+            if used + spans <= allocated && total + spans <= self.max_spans_per_second {
+                window.used[index] += spans;
+                return decision;
+            }
+        }
+
+        Decision::drop()
+    }
+}
+
+impl TailSampler for RateAllocationSampler {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        self.sample_at(trace, Instant::now())
+    }
+}
+
+impl std::fmt::Debug for RateAllocationSampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let allocations = self.policies.iter().map(|(_, spans)| spans);
+        f.debug_struct("RateAllocationSampler")
+            .field("max_spans_per_second", &self.max_spans_per_second)
+            .field("allocations", &allocations.collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use crate::opentelemetry::sampler::BufferedSpan;
+
+    struct Fixed(bool);
+
+    impl TailSampler for Fixed {
+        fn sample(&self, _trace: &BufferedTrace<'_>) -> Decision {
+            if self.0 {
+                Decision::keep()
+            } else {
+                Decision::drop()
+            }
+        }
+    }
+
+    fn three_spans() -> Vec<BufferedSpan> {
+        vec![span("a"), span("b"), span("root")]
+    }
+
+    fn kept(sampler: &RateAllocationSampler, traces: usize, now: Instant) -> usize {
+        let spans = three_spans();
+        let trace = BufferedTrace::new(&spans);
+        (0..traces)
+            .filter(|_| sampler.sample_at(&trace, now).record_trace())
+            .count()
+    }
+
+    #[test]
+    fn respects_allocation() {
+        let sampler = RateAllocationSampler::new(10.0)
+            .with_allocation(Fixed(true), 50.0)
+            .with_allocation(Fixed(false), 50.0);
+
+        assert_eq!(kept(&sampler, 5, Instant::now()), 1);
+    }
+
+    #[test]
+    fn unused_budget_flows_to_later_policies() {
+        let sampler = RateAllocationSampler::new(10.0)
+            .with_allocation(Fixed(false), 50.0)
+            .with_allocation(Fixed(true), 50.0);
+
+        assert_eq!(kept(&sampler, 5, Instant::now()), 3);
+    }
+
+    #[test]
+    fn overflows_to_later_policies() {
+        let sampler = RateAllocationSampler::new(12.0)
+            .with_allocation(Fixed(true), 50.0)
+            .with_allocation(Fixed(true), 50.0);
+
+        assert_eq!(kept(&sampler, 5, Instant::now()), 4);
+    }
+
+    #[test]
+    fn caps_total_budget() {
+        let sampler = RateAllocationSampler::new(6.0)
+            .with_allocation(Fixed(true), 100.0)
+            .with_allocation(Fixed(true), 100.0);
+
+        assert_eq!(kept(&sampler, 5, Instant::now()), 2);
+    }
+
+    #[test]
+    fn resets_every_second() {
+        let sampler = RateAllocationSampler::new(3.0).with_allocation(Fixed(true), 100.0);
+        let now = Instant::now();
+
+        assert_eq!(kept(&sampler, 2, now), 1);
+        assert_eq!(kept(&sampler, 2, now + Duration::from_millis(999)), 0);
+        assert_eq!(kept(&sampler, 2, now + Duration::from_secs(1)), 1);
+    }
+}
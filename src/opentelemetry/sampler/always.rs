@@ -0,0 +1,21 @@
+use crate::opentelemetry::sampler::{BufferedTrace, Decision, TailSampler};
+
+/// Keeps every trace.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AlwaysOn;
+
+impl TailSampler for AlwaysOn {
+    fn sample(&self, _trace: &BufferedTrace<'_>) -> Decision {
+        Decision::keep().with_policy("always_on")
+    }
+}
+
+/// Drops every trace.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AlwaysOff;
+
+impl TailSampler for AlwaysOff {
+    fn sample(&self, _trace: &BufferedTrace<'_>) -> Decision {
+        Decision::drop()
+    }
+}
@@ -0,0 +1,214 @@
+use crate::opentelemetry::sampler::{BufferedTrace, Decision, TailSampler};
+use opentelemetry::{Key, Value};
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Keeps traces in which any span carries an attribute matching one of the configured rules.
+///
+/// String rules compare the string representation of the attribute value, so they also match
+/// numeric or boolean attributes. Numeric ranges match numeric values, and strings parsing as
+/// numbers.
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::AttributeSampler;
+/// use regex::Regex;
+///
+/// let sampler = AttributeSampler::new()
+///     .equals("customer.tier", "enterprise")
+///     .in_range("http.status_code", 500.0, 599.0)
+///     .matches("http.route", Regex::new("^/admin/").unwrap());
+/// # drop(sampler);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct AttributeSampler {
+    rules: Vec<(Key, AttributeMatch)>,
+}
+
+#[derive(Debug, Clone)]
+enum AttributeMatch {
+    Equals(String),
+    OneOf(HashSet<String>),
+    InRange(f64, f64),
+    Matches(Regex),
+}
+
+impl AttributeMatch {
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            AttributeMatch::Equals(expected) => value.as_str() == expected.as_str(),
+            AttributeMatch::OneOf(expected) => expected.contains(value.as_str().as_ref()),
+            AttributeMatch::InRange(min, max) => {
+                let number = match value {
+                    Value::I64(i) => Some(*i as f64),
+                    Value::F64(f) => Some(*f),
+                    Value::String(s) => s.as_str().parse().ok(),
+                    _ => None,
+                };
+                matches!(number, Some(n) if *min <= n && n <= *max)
+            }
+            AttributeMatch::Matches(regex) => regex.is_match(&value.as_str()),
+        }
+    }
+}
+
+impl AttributeSampler {
+    /// Create a sampler without rules, which drops every trace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep traces where `key` is exactly `value`.
+    pub fn equals<K, V>(self, key: K, value: V) -> Self
+    where
+        K: Into<Key>,
+        V: Into<String>,
+    {
+        self.rule(key, AttributeMatch::Equals(value.into()))
+    }
+
+    /// Keep traces where `key` is any of `values`.
+    pub fn one_of<K, I>(self, key: K, values: I) -> Self
+    where
+        K: Into<Key>,
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let values = values.into_iter().map(Into::into).collect();
+        self.rule(key, AttributeMatch::OneOf(values))
+    }
+
+    /// Keep traces where `key` is a number between `min` and `max`, inclusive.
+    ///
+    /// Integer, float and numeric string attributes are compared as `f64`.
+    pub fn in_range<K>(self, key: K, min: f64, max: f64) -> Self
+    where
+        K: Into<Key>,
+    {
+        self.rule(key, AttributeMatch::InRange(min, max))
+    }
+
+    /// Keep traces where `key` matches `regex`.
+    pub fn matches<K>(self, key: K, regex: Regex) -> Self
+    where
+        K: Into<Key>,
+    {
+        self.rule(key, AttributeMatch::Matches(regex))
+    }
+
+    fn rule<K: Into<Key>>(mut self, key: K, rule: AttributeMatch) -> Self {
+        self.rules.push((key.into(), rule));
+        self
+    }
+}
+
+impl TailSampler for AttributeSampler {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        let matched = trace
+            .spans()
+            .iter()
+            .flat_map(|span| span.data.builder.attributes.iter().flatten())
+            .find(|attribute| {
+                self.rules
+                    .iter()
+                    .any(|(key, rule)| *key == attribute.key && rule.matches(&attribute.value))
+            });
+
+        match matched {
+            Some(attribute) => Decision::keep()
+                .with_policy("attribute")
+                .with_rule(format!("{}={}", attribute.key, attribute.value)),
+            None => Decision::drop(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use crate::opentelemetry::sampler::BufferedSpan;
+    use opentelemetry::KeyValue;
+
+    fn trace_with(attributes: Vec<KeyValue>) -> Vec<BufferedSpan> {
+        let mut child = span("child");
+        child.data.builder.attributes = Some(attributes);
+        vec![child, span("root")]
+    }
+
+    fn keeps(sampler: &AttributeSampler, attributes: Vec<KeyValue>) -> bool {
+        let spans = trace_with(attributes);
+        sampler.sample(&BufferedTrace::new(&spans)).record_trace()
+    }
+
+    #[test]
+    fn equals() {
+        let sampler = AttributeSampler::new().equals("customer.tier", "enterprise");
+
+        assert!(keeps(
+            &sampler,
+            vec![KeyValue::new("customer.tier", "enterprise")]
+        ));
+        assert!(!keeps(
+            &sampler,
+            vec![KeyValue::new("customer.tier", "free")]
+        ));
+        assert!(!keeps(&sampler, vec![KeyValue::new("tier", "enterprise")]));
+    }
+
+    #[test]
+    fn one_of() {
+        let sampler = AttributeSampler::new().one_of("region", ["eu", "us"]);
+
+        assert!(keeps(&sampler, vec![KeyValue::new("region", "eu")]));
+        assert!(!keeps(&sampler, vec![KeyValue::new("region", "ap")]));
+    }
+
+    #[test]
+    fn in_range() {
+        let sampler = AttributeSampler::new().in_range("http.status_code", 500.0, 599.0);
+
+        assert!(keeps(
+            &sampler,
+            vec![KeyValue::new("http.status_code", 503)]
+        ));
+        assert!(keeps(
+            &sampler,
+            vec![KeyValue::new("http.status_code", "500")]
+        ));
+        assert!(keeps(
+            &sampler,
+            vec![KeyValue::new("http.status_code", 599.0)]
+        ));
+        assert!(!keeps(
+            &sampler,
+            vec![KeyValue::new("http.status_code", 404)]
+        ));
+        assert!(!keeps(
+            &sampler,
+            vec![KeyValue::new("http.status_code", true)]
+        ));
+
+        let spans = trace_with(vec![KeyValue::new("http.status_code", 503)]);
+        let decision = sampler.sample(&BufferedTrace::new(&spans));
+        assert_eq!(decision.policy(), Some("attribute"));
+        assert_eq!(decision.rule(), Some("http.status_code=503"));
+    }
+
+    #[test]
+    fn matches() {
+        let sampler = AttributeSampler::new().matches("route", Regex::new("^/send").unwrap());
+
+        assert!(keeps(&sampler, vec![KeyValue::new("route", "/send/42")]));
+        assert!(!keeps(&sampler, vec![KeyValue::new("route", "/resend")]));
+    }
+
+    #[test]
+    fn no_rules() {
+        assert!(!keeps(
+            &AttributeSampler::new(),
+            vec![KeyValue::new("route", "/send")]
+        ));
+    }
+}
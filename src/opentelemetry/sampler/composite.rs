@@ -0,0 +1,152 @@
+use crate::opentelemetry::sampler::{BufferedTrace, Decision, TailSampler};
+
+/// Keeps traces kept by both samplers.
+///
+/// The second sampler is only consulted when the first keeps the trace, and labels the decision.
+/// The probabilities with which both samplers kept the trace are multiplied. Created with
+/// [`TailSampler::and`].
+#[derive(Debug, Clone)]
+pub struct AndSampler<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: TailSampler, B: TailSampler> TailSampler for AndSampler<A, B> {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        let decision = self.first.sample(trace);
+        if decision.record_trace() {
+            let second = self.second.sample(trace);
+            let probability = decision.probability() * second.probability();
+            second.with_probability(probability)
+        } else {
+            decision
+        }
+    }
+}
+
+/// Keeps traces kept by either sampler.
+///
+/// The second sampler is only consulted when the first drops the trace. Created with
+/// [`TailSampler::or`].
+#[derive(Debug, Clone)]
+pub struct OrSampler<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: TailSampler, B: TailSampler> TailSampler for OrSampler<A, B> {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        let decision = self.first.sample(trace);
+        if decision.record_trace() {
+            decision
+        } else {
+            self.second.sample(trace)
+        }
+    }
+}
+
+/// Keeps the traces dropped by a sampler, and drops the ones it keeps.
+///
+/// Created with [`TailSampler::not`].
+#[derive(Debug, Clone)]
+pub struct NotSampler<S> {
+    inner: S,
+}
+
+impl<S: TailSampler> TailSampler for NotSampler<S> {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        if self.inner.sample(trace).record_trace() {
+            Decision::drop()
+        } else {
+            Decision::keep().with_policy("not")
+        }
+    }
+}
+
+pub(crate) fn and<A, B>(first: A, second: B) -> AndSampler<A, B> {
+    AndSampler { first, second }
+}
+
+pub(crate) fn or<A, B>(first: A, second: B) -> OrSampler<A, B> {
+    OrSampler { first, second }
+}
+
+pub(crate) fn not<S>(inner: S) -> NotSampler<S> {
+    NotSampler { inner }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Fixed {
+        record_trace: bool,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Fixed {
+        fn new(record_trace: bool) -> Self {
+            Fixed {
+                record_trace,
+                ..Default::default()
+            }
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    impl TailSampler for Fixed {
+        fn sample(&self, _trace: &BufferedTrace<'_>) -> Decision {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.record_trace {
+                Decision::keep()
+            } else {
+                Decision::drop()
+            }
+        }
+    }
+
+    fn keeps(sampler: impl TailSampler) -> bool {
+        let spans = [span("root")];
+        sampler.sample(&BufferedTrace::new(&spans)).record_trace()
+    }
+
+    #[test]
+    fn truth_tables() {
+        for (a, b) in [(false, false), (false, true), (true, false), (true, true)] {
+            assert_eq!(keeps(Fixed::new(a).and(Fixed::new(b))), a && b);
+            assert_eq!(keeps(Fixed::new(a).or(Fixed::new(b))), a || b);
+        }
+        assert!(keeps(Fixed::new(false).not()));
+        assert!(!keeps(Fixed::new(true).not()));
+    }
+
+    #[test]
+    fn short_circuits() {
+        let second = Fixed::new(true);
+        assert!(!keeps(Fixed::new(false).and(second.clone())));
+        assert_eq!(second.calls(), 0);
+
+        assert!(keeps(Fixed::new(true).or(second.clone())));
+        assert_eq!(second.calls(), 0);
+
+        assert!(keeps(Fixed::new(false).or(second.clone())));
+        assert_eq!(second.calls(), 1);
+    }
+
+    #[test]
+    fn nests() {
+        // error OR (slow AND route)
+        let route = Fixed::new(true);
+        let sampler = Fixed::new(false).or(Fixed::new(false).and(route.clone()));
+
+        assert!(!keeps(sampler));
+        assert_eq!(route.calls(), 0);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::opentelemetry::sampler::{BufferedSpan, BufferedTrace, Decision, TailSampler};
+use opentelemetry::trace::{SpanKind, Status};
+use opentelemetry::Value;
+use std::collections::HashSet;
+
+const HTTP_STATUS_CODE: &str = "http.status_code";
+const GRPC_STATUS_CODE: &str = "rpc.grpc.status_code";
+
+/// Keeps traces in which a call to a dependency failed, even if the trace itself succeeded.
+///
+/// Only spans with `otel.kind = "client"` are considered. Such a span failed if it ended with an
+/// [`Error`] status, or if its `http.status_code` or `rpc.grpc.status_code` attribute is one of
+/// the configured failure codes.
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::DownstreamFailureSampler;
+///
+/// // UNKNOWN, DEADLINE_EXCEEDED, INTERNAL and UNAVAILABLE.
+/// let sampler = DownstreamFailureSampler::new()
+///     .http_status_codes(500..=599)
+///     .grpc_status_codes([2, 4, 13, 14]);
+/// # drop(sampler);
+/// ```
+///
+/// [`Error`]: opentelemetry::trace::Status::Error
+#[derive(Debug, Clone, Default)]
+pub struct DownstreamFailureSampler {
+    http_status_codes: HashSet<i64>,
+    grpc_status_codes: HashSet<i64>,
+}
+
+impl DownstreamFailureSampler {
+    /// Create a sampler keeping traces where a client span ended with an error status.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also treat client spans with an `http.status_code` in `codes` as failed.
+    pub fn http_status_codes<I>(mut self, codes: I) -> Self
+    where
+        I: IntoIterator<Item = i64>,
+    {
+        self.http_status_codes.extend(codes);
+        self
+    }
+
+    /// Also treat client spans with an `rpc.grpc.status_code` in `codes` as failed.
+    pub fn grpc_status_codes<I>(mut self, codes: I) -> Self
+    where
+        I: IntoIterator<Item = i64>,
+    {
+        self.grpc_status_codes.extend(codes);
+        self
+    }
+
+    /// Why `span` failed, if it did.
+    fn failure(&self, span: &BufferedSpan) -> Option<String> {
+        let builder = &span.data.builder;
+        if builder.span_kind != Some(SpanKind::Client) {
+            return None;
+        }
+
+        if matches!(builder.status, Status::Error { .. }) {
+            return Some(format!("error:{}", builder.name));
+        }
+
+        builder.attributes.iter().flatten().find_map(|attribute| {
+            let codes = match attribute.key.as_str() {
+                HTTP_STATUS_CODE => &self.http_status_codes,
+                GRPC_STATUS_CODE => &self.grpc_status_codes,
+                _ => return None,
+            };
+            let code = match &attribute.value {
+                Value::I64(code) => Some(*code),
+                Value::String(code) => code.as_str().parse().ok(),
+                _ => None,
+            }?;
+
+            codes
+                .contains(&code)
+                .then(|| format!("{}={}", attribute.key, code))
+        })
+    }
+}
+
+impl TailSampler for DownstreamFailureSampler {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        match trace.spans().iter().find_map(|span| self.failure(span)) {
+            Some(rule) => Decision::keep()
+                .with_policy("downstream_failure")
+                .with_rule(rule),
+            None => Decision::drop(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use opentelemetry::KeyValue;
+
+    fn client(status: Status, attributes: Vec<KeyValue>) -> BufferedSpan {
+        let mut client = span("client");
+        client.data.builder.span_kind = Some(SpanKind::Client);
+        client.data.builder.status = status;
+        client.data.builder.attributes = Some(attributes);
+        client
+    }
+
+    fn sample(sampler: &DownstreamFailureSampler, child: BufferedSpan) -> Decision {
+        let mut root = span("root");
+        root.data.builder.status = Status::Ok;
+        let spans = [child, root];
+        sampler.sample(&BufferedTrace::new(&spans))
+    }
+
+    #[test]
+    fn keeps_client_errors() {
+        let sampler = DownstreamFailureSampler::new();
+
+        let decision = sample(&sampler, client(Status::error("timeout"), vec![]));
+        assert!(decision.record_trace());
+        assert_eq!(decision.policy(), Some("downstream_failure"));
+        assert_eq!(decision.rule(), Some("error:client"));
+
+        let mut internal = client(Status::error("timeout"), vec![]);
+        internal.data.builder.span_kind = Some(SpanKind::Internal);
+        assert!(!sample(&sampler, internal).record_trace());
+    }
+
+    #[test]
+    fn keeps_failure_codes() {
+        let sampler = DownstreamFailureSampler::new()
+            .http_status_codes(500..=599)
+            .grpc_status_codes([14]);
+
+        let decision = sample(
+            &sampler,
+            client(Status::Unset, vec![KeyValue::new(HTTP_STATUS_CODE, 503)]),
+        );
+        assert!(decision.record_trace());
+        assert_eq!(decision.rule(), Some("http.status_code=503"));
+
+        let unavailable = client(Status::Unset, vec![KeyValue::new(GRPC_STATUS_CODE, "14")]);
+        assert!(sample(&sampler, unavailable).record_trace());
+
+        let not_found = client(Status::Unset, vec![KeyValue::new(HTTP_STATUS_CODE, 404)]);
+        assert!(!sample(&sampler, not_found).record_trace());
+
+        // Codes of one protocol don't apply to the other.
+        let mismatched = client(Status::Unset, vec![KeyValue::new(GRPC_STATUS_CODE, 503)]);
+        assert!(!sample(&sampler, mismatched).record_trace());
+    }
+}
@@ -0,0 +1,91 @@
+use crate::opentelemetry::sampler::{BufferedTrace, Decision, TailSampler};
+use opentelemetry::trace as otel;
+
+/// Keeps traces in which something failed.
+///
+/// A trace is kept if any of its spans ended with an [`Error`] status, or recorded an
+/// [`ERROR`]-level event.
+///
+/// [`Error`]: opentelemetry::trace::Status::Error
+/// [`ERROR`]: tracing::Level::ERROR
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ErrorSampler;
+
+impl TailSampler for ErrorSampler {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        let failed = trace.spans().iter().find(|span| {
+            let builder = &span.data.builder;
+            matches!(builder.status, otel::Status::Error { .. })
+                || builder
+                    .events
+                    .iter()
+                    .flatten()
+                    .any(|event| event.attributes.iter().any(is_error_level))
+        });
+
+        match failed {
+            Some(span) => Decision::keep()
+                .with_policy("error")
+                .with_rule(format!("error:{}", span.data.builder.name)),
+            None => Decision::drop(),
+        }
+    }
+}
+
+fn is_error_level(attribute: &opentelemetry::KeyValue) -> bool {
+    attribute.key.as_str() == "level"
+        && attribute.value.as_str() == tracing_core::Level::ERROR.as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use opentelemetry::{Key, KeyValue};
+    use std::time::SystemTime;
+
+    fn event(level: tracing::Level) -> otel::Event {
+        otel::Event::new(
+            "",
+            SystemTime::now(),
+            vec![Key::new("level").string(level.to_string())],
+            0,
+        )
+    }
+
+    #[test]
+    fn keeps_error_status() {
+        let mut failed = span("child");
+        failed.data.builder.status = otel::Status::error("boom");
+        let spans = [failed, span("root")];
+
+        let decision = ErrorSampler.sample(&BufferedTrace::new(&spans));
+        assert!(decision.record_trace());
+        assert_eq!(decision.policy(), Some("error"));
+        assert_eq!(decision.rule(), Some("error:child"));
+    }
+
+    #[test]
+    fn keeps_error_events() {
+        let mut root = span("root");
+        root.data.builder.status = otel::Status::Ok;
+        root.data.builder.events = Some(vec![event(tracing::Level::ERROR)]);
+        let spans = [root];
+
+        assert!(ErrorSampler
+            .sample(&BufferedTrace::new(&spans))
+            .record_trace());
+    }
+
+    #[test]
+    fn drops_successful_traces() {
+        let mut root = span("root");
+        root.data.builder.events = Some(vec![event(tracing::Level::WARN)]);
+        root.data.builder.attributes = Some(vec![KeyValue::new("level", "ERROR")]);
+        let spans = [span("child"), root];
+
+        assert!(!ErrorSampler
+            .sample(&BufferedTrace::new(&spans))
+            .record_trace());
+    }
+}
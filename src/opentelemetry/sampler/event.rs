@@ -0,0 +1,184 @@
+use crate::opentelemetry::sampler::{BufferedTrace, Decision, TailSampler};
+use opentelemetry::trace as otel;
+use regex::Regex;
+use std::borrow::Cow;
+use tracing_core::Level;
+
+/// Keeps traces in which any span recorded an event matching one of the configured rules.
+///
+/// Events are matched on the `level` and `target` attributes recorded for every `tracing` event,
+/// and on their message.
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::EventSampler;
+/// use regex::Regex;
+///
+/// let sampler = EventSampler::new()
+///     .at_or_above(tracing::Level::WARN)
+///     .target_prefix("sqlx::")
+///     .message_matches(Regex::new("(?i)retrying").unwrap());
+/// # drop(sampler);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EventSampler {
+    rules: Vec<EventMatch>,
+}
+
+#[derive(Debug, Clone)]
+enum EventMatch {
+    AtOrAbove(Level),
+    TargetPrefix(Cow<'static, str>),
+    MessageMatches(Regex),
+}
+
+impl EventMatch {
+    fn matches(&self, event: &otel::Event) -> bool {
+        match self {
+            EventMatch::AtOrAbove(min) => {
+                let level = attribute(event, "level").and_then(|level| level.parse::<Level>().ok());
+                // More verbose levels compare greater.
+                matches!(level, Some(level) if level <= *min)
+            }
+            EventMatch::TargetPrefix(prefix) => {
+                let target = attribute(event, "target");
+                matches!(target, Some(target) if target.starts_with(prefix.as_ref()))
+            }
+            EventMatch::MessageMatches(regex) => regex.is_match(&event.name),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            EventMatch::AtOrAbove(level) => format!("level>={}", level),
+            EventMatch::TargetPrefix(prefix) => format!("target:{}", prefix),
+            EventMatch::MessageMatches(regex) => format!("message:{}", regex),
+        }
+    }
+}
+
+fn attribute<'e>(event: &'e otel::Event, key: &str) -> Option<Cow<'e, str>> {
+    event
+        .attributes
+        .iter()
+        .find(|attribute| attribute.key.as_str() == key)
+        .map(|attribute| attribute.value.as_str())
+}
+
+impl EventSampler {
+    /// Create a sampler without rules, which drops every trace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep traces with an event at `level` or more severe.
+    pub fn at_or_above(self, level: Level) -> Self {
+        self.rule(EventMatch::AtOrAbove(level))
+    }
+
+    /// Keep traces with an event whose target starts with `prefix`.
+    pub fn target_prefix<P>(self, prefix: P) -> Self
+    where
+        P: Into<Cow<'static, str>>,
+    {
+        self.rule(EventMatch::TargetPrefix(prefix.into()))
+    }
+
+    /// Keep traces with an event whose message matches `regex`.
+    pub fn message_matches(self, regex: Regex) -> Self {
+        self.rule(EventMatch::MessageMatches(regex))
+    }
+
+    fn rule(mut self, rule: EventMatch) -> Self {
+        self.rules.push(rule);
+        self
+    }
+}
+
+impl TailSampler for EventSampler {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        let matched = trace
+            .spans()
+            .iter()
+            .flat_map(|span| span.data.builder.events.iter().flatten())
+            .find_map(|event| self.rules.iter().find(|rule| rule.matches(event)));
+
+        match matched {
+            Some(rule) => Decision::keep()
+                .with_policy("event")
+                .with_rule(rule.describe()),
+            None => Decision::drop(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use crate::opentelemetry::sampler::BufferedSpan;
+    use opentelemetry::Key;
+    use std::time::SystemTime;
+
+    fn event(level: Level, target: &'static str, message: &'static str) -> otel::Event {
+        otel::Event::new(
+            message,
+            SystemTime::now(),
+            vec![
+                Key::new("level").string(level.to_string()),
+                Key::new("target").string(target),
+            ],
+            0,
+        )
+    }
+
+    fn trace_with(event: otel::Event) -> Vec<BufferedSpan> {
+        let mut child = span("child");
+        child.data.builder.events = Some(vec![event]);
+        vec![child, span("root")]
+    }
+
+    fn keeps(sampler: &EventSampler, event: otel::Event) -> bool {
+        let spans = trace_with(event);
+        sampler.sample(&BufferedTrace::new(&spans)).record_trace()
+    }
+
+    #[test]
+    fn at_or_above() {
+        let sampler = EventSampler::new().at_or_above(Level::WARN);
+
+        assert!(keeps(&sampler, event(Level::WARN, "app", "slow")));
+        assert!(keeps(&sampler, event(Level::ERROR, "app", "failed")));
+        assert!(!keeps(&sampler, event(Level::INFO, "app", "done")));
+
+        let spans = trace_with(event(Level::ERROR, "app", "failed"));
+        let decision = sampler.sample(&BufferedTrace::new(&spans));
+        assert_eq!(decision.policy(), Some("event"));
+        assert_eq!(decision.rule(), Some("level>=WARN"));
+    }
+
+    #[test]
+    fn target_prefix() {
+        let sampler = EventSampler::new().target_prefix("sqlx::");
+
+        assert!(keeps(&sampler, event(Level::DEBUG, "sqlx::query", "")));
+        assert!(!keeps(&sampler, event(Level::DEBUG, "app::sqlx", "")));
+    }
+
+    #[test]
+    fn message_matches() {
+        let sampler = EventSampler::new().message_matches(Regex::new("^retrying").unwrap());
+
+        assert!(keeps(&sampler, event(Level::INFO, "app", "retrying in 1s")));
+        assert!(!keeps(&sampler, event(Level::INFO, "app", "not retrying")));
+    }
+
+    #[test]
+    fn no_events() {
+        let sampler = EventSampler::new().at_or_above(Level::TRACE);
+        let spans = [span("root")];
+
+        assert!(!sampler.sample(&BufferedTrace::new(&spans)).record_trace());
+    }
+}
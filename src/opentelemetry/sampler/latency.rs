@@ -0,0 +1,116 @@
+use crate::opentelemetry::sampler::{find_by_name, BufferedTrace, Decision, TailSampler};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Keeps traces whose root span took longer than a threshold.
+///
+/// The threshold can be overridden per root span, keyed either by its `otel.name` or by the name
+/// of the `tracing` span. The `otel.name` override is looked up first. Partial traces, whose root
+/// span is still open, are dropped.
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::LatencySampler;
+/// use std::time::Duration;
+///
+/// let sampler = LatencySampler::new(Duration::from_millis(500))
+///     .with_threshold("POST /send", Duration::from_secs(2))
+///     .with_threshold("health_check", Duration::from_millis(50));
+/// # drop(sampler);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LatencySampler {
+    threshold: Duration,
+    thresholds: HashMap<Cow<'static, str>, Duration>,
+}
+
+impl LatencySampler {
+    /// Create a sampler keeping traces whose root span lasted longer than `threshold`.
+    pub fn new(threshold: Duration) -> Self {
+        LatencySampler {
+            threshold,
+            thresholds: HashMap::new(),
+        }
+    }
+
+    /// Overrides the threshold for root spans named `name`.
+    pub fn with_threshold<N>(mut self, name: N, threshold: Duration) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+    {
+        self.thresholds.insert(name.into(), threshold);
+        self
+    }
+}
+
+impl TailSampler for LatencySampler {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        let root = match trace.root() {
+            Some(root) => root,
+            None => return Decision::drop(),
+        };
+        let threshold = find_by_name(&self.thresholds, root).unwrap_or(&self.threshold);
+
+        match root.duration() {
+            Some(duration) if duration > *threshold => Decision::keep()
+                .with_policy("latency")
+                .with_rule(format!("slow:{}", root.data.builder.name)),
+            _ => Decision::drop(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use crate::opentelemetry::sampler::BufferedSpan;
+
+    fn root(name: &'static str, otel_name: &'static str, duration: Duration) -> BufferedSpan {
+        let mut root = span(name);
+        let builder = &mut root.data.builder;
+        builder.name = otel_name.into();
+        builder.end_time = builder.start_time.map(|start| start + duration);
+        root
+    }
+
+    #[test]
+    fn keeps_slow_traces() {
+        let sampler = LatencySampler::new(Duration::from_millis(100));
+
+        let slow = [root("request", "request", Duration::from_millis(150))];
+        assert!(sampler.sample(&BufferedTrace::new(&slow)).record_trace());
+
+        let fast = [root("request", "request", Duration::from_millis(50))];
+        assert!(!sampler.sample(&BufferedTrace::new(&fast)).record_trace());
+
+        // The last span of a partial trace is not its root.
+        assert!(!sampler
+            .sample(&BufferedTrace::partial(&slow))
+            .record_trace());
+    }
+
+    #[test]
+    fn thresholds_by_name() {
+        let sampler = LatencySampler::new(Duration::from_millis(100))
+            .with_threshold("request", Duration::from_millis(10))
+            .with_threshold("POST /send", Duration::from_secs(1));
+
+        let by_span_name = [root("request", "GET /", Duration::from_millis(50))];
+        assert!(sampler
+            .sample(&BufferedTrace::new(&by_span_name))
+            .record_trace());
+
+        let by_otel_name = [root("request", "POST /send", Duration::from_millis(500))];
+        assert!(!sampler
+            .sample(&BufferedTrace::new(&by_otel_name))
+            .record_trace());
+
+        let slow = [root("request", "POST /send", Duration::from_secs(2))];
+        let decision = sampler.sample(&BufferedTrace::new(&slow));
+        assert_eq!(decision.policy(), Some("latency"));
+        assert_eq!(decision.rule(), Some("slow:POST /send"));
+    }
+}
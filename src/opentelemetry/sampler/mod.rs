@@ -0,0 +1,446 @@
+//! Tail sampling of complete traces.
+//!
+//! When the [`TraceContextLayer`] is installed below the [`OpenTelemetryLayer`], finished spans are
+//! buffered per trace instead of being exported as they close. Once the root span of a trace
+//! closes, the [`TailSampler`] configured with [`OpenTelemetryLayer::with_tail_sampler`] is handed
+//! the complete trace and decides whether it is exported or discarded.
+//!
+//! A [`SampleDecision`] inserted into the trace's extensions by application code takes precedence
+//! over the configured sampler. A decision made before the root span closes takes effect as soon
+//! as another span of the trace opens or closes:
+//!
+//! * When keeping the trace, the spans buffered so far are exported, and later spans are exported
+//!   as they close.
+//! * When dropping the trace, the spans buffered so far are released. Later spans only carry their
+//!   ids, for propagation, and record neither fields nor events.
+//!
+//! ## Combining samplers
+//!
+//! Samplers are combined with [`TailSampler::and`], [`TailSampler::or`] and [`TailSampler::not`].
+//! Combinations are evaluated left to right and short-circuit, so a sampler is only consulted
+//! when its verdict can still change the outcome.
+//!
+//! ```
+//! use onesignal_tracing_tail_sample::opentelemetry::sampler::{
+//!     AttributeSampler, ErrorSampler, LatencySampler, TailSampler,
+//! };
+//! use std::time::Duration;
+//!
+//! // error OR (slow AND route=/send), but never health checks.
+//! let slow = LatencySampler::new(Duration::from_millis(500));
+//! let send = AttributeSampler::new().equals("http.route", "/send");
+//! let health_check = AttributeSampler::new().equals("http.route", "/healthz");
+//! let sampler = health_check.not().and(ErrorSampler.or(slow.and(send)));
+//! # drop(sampler);
+//! ```
+//!
+//! [`TraceContextLayer`]: crate::TraceContextLayer
+//! [`OpenTelemetryLayer`]: crate::opentelemetry::OpenTelemetryLayer
+//! [`OpenTelemetryLayer::with_tail_sampler`]: crate::opentelemetry::OpenTelemetryLayer::with_tail_sampler
+//! [`SampleDecision`]: crate::SampleDecision
+use crate::opentelemetry::OtelData;
+use opentelemetry::trace::{SpanId, TraceContextExt, TraceId};
+use std::borrow::{Borrow, Cow};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod adaptive;
+mod allocation;
+mod always;
+mod attribute;
+mod composite;
+mod downstream;
+mod error;
+mod event;
+mod latency;
+mod probabilistic;
+mod prune;
+mod rate_limit;
+mod route;
+mod shape;
+mod view;
+
+pub use adaptive::AdaptiveSampler;
+pub use allocation::RateAllocationSampler;
+pub use always::{AlwaysOff, AlwaysOn};
+pub use attribute::AttributeSampler;
+pub use composite::{AndSampler, NotSampler, OrSampler};
+pub use downstream::DownstreamFailureSampler;
+pub use error::ErrorSampler;
+pub use event::EventSampler;
+pub use latency::LatencySampler;
+pub use probabilistic::TraceIdRatioSampler;
+pub use prune::SpanPruner;
+pub use rate_limit::{RateLimit, RateLimitingSampler};
+pub use route::RouteSampler;
+pub use shape::TraceShapeSampler;
+pub use view::{from_fn, FnSampler, SpanView, TraceView};
+
+/// A finished span held in the buffer of its trace.
+#[derive(Debug, Clone)]
+pub struct BufferedSpan {
+    /// The OpenTelemetry data recorded for the span.
+    pub data: OtelData,
+
+    /// The name of the `tracing` span, ignoring any `otel.name` override.
+    pub name: &'static str,
+
+    /// The target of the `tracing` span.
+    pub target: &'static str,
+}
+
+impl BufferedSpan {
+    /// The OpenTelemetry id of the span.
+    pub fn span_id(&self) -> SpanId {
+        self.data.builder.span_id.unwrap_or(SpanId::INVALID)
+    }
+
+    /// The OpenTelemetry id of the parent span, which may be remote.
+    pub fn parent_span_id(&self) -> Option<SpanId> {
+        if self.data.parent_cx.has_active_span() {
+            Some(self.data.parent_cx.span().span_context().span_id())
+        } else {
+            None
+        }
+    }
+
+    /// How long the span was open, if both its start and end time were recorded.
+    pub fn duration(&self) -> Option<Duration> {
+        let builder = &self.data.builder;
+        let (start, end) = (builder.start_time?, builder.end_time?);
+        Some(end.duration_since(start).unwrap_or_default())
+    }
+}
+
+/// Looks `span` up by its `otel.name`, falling back to the name of the `tracing` span.
+fn find_by_name<'m, K, V>(map: &'m HashMap<K, V>, span: &BufferedSpan) -> Option<&'m V>
+where
+    K: Borrow<str> + Eq + Hash,
+{
+    map.get(name_in(map, span))
+}
+
+/// The name `span` is found by in `map`: its `otel.name`, unless only the name of the `tracing`
+/// span is in `map`.
+fn name_in<'s, K, V>(map: &HashMap<K, V>, span: &'s BufferedSpan) -> &'s str
+where
+    K: Borrow<str> + Eq + Hash,
+{
+    let name = span.data.builder.name.as_ref();
+    if !map.contains_key(name) && map.contains_key(span.name) {
+        span.name
+    } else {
+        name
+    }
+}
+
+/// The buffered spans of a trace.
+///
+/// Traces are usually sampled once their root span closed. A trace decided before, as it
+/// overflows the span cap or the memory budget, or outlives the maximum trace age, is partial: it
+/// has no root span yet.
+#[derive(Debug, Clone, Copy)]
+pub struct BufferedTrace<'a> {
+    spans: &'a [BufferedSpan],
+    partial: bool,
+}
+
+impl<'a> BufferedTrace<'a> {
+    /// Wraps the buffered spans of a trace, in the order they closed.
+    ///
+    /// The root span closes last, so it must be the last element of `spans`.
+    ///
+    /// ## Panics
+    ///
+    /// If `spans` is empty.
+    pub fn new(spans: &'a [BufferedSpan]) -> Self {
+        assert!(!spans.is_empty(), "a trace contains at least its root span");
+        BufferedTrace {
+            spans,
+            partial: false,
+        }
+    }
+
+    /// Wraps the buffered spans of a trace whose root span is still open, in the order they
+    /// closed.
+    ///
+    /// ## Panics
+    ///
+    /// If `spans` is empty.
+    pub fn partial(spans: &'a [BufferedSpan]) -> Self {
+        assert!(
+            !spans.is_empty(),
+            "a trace is sampled once it buffers a span"
+        );
+        BufferedTrace {
+            spans,
+            partial: true,
+        }
+    }
+
+    /// The root span of the trace, i.e. the last of its spans, unless the trace is partial.
+    pub fn root(&self) -> Option<&'a BufferedSpan> {
+        if self.partial {
+            None
+        } else {
+            self.spans.last()
+        }
+    }
+
+    /// Whether the trace is sampled before its root span closed.
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+
+    /// All spans of the trace, including the root, in the order they closed.
+    pub fn spans(&self) -> &'a [BufferedSpan] {
+        self.spans
+    }
+
+    /// The OpenTelemetry trace id, inherited from the remote parent of the root span if any.
+    pub fn trace_id(&self) -> TraceId {
+        // Spans within the trace inherit the trace id of their parent.
+        let span = &self.spans[self.spans.len() - 1].data;
+        if span.parent_cx.has_active_span() {
+            span.parent_cx.span().span_context().trace_id()
+        } else {
+            span.builder.trace_id.unwrap_or(TraceId::INVALID)
+        }
+    }
+}
+
+/// The verdict of a [`TailSampler`] on a trace.
+///
+/// A kept trace can be labelled with the policy and rule that kept it. The labels are recorded
+/// on the exported root span as the `sampling.policy` and `sampling.rule` attributes.
+///
+/// Samplers keeping only a fraction of the traces also report the probability with which the
+/// trace was kept. Every span of a trace kept with a probability below 1 records the number of
+/// traces it stands for as the `sampling.adjusted_count` attribute, so that backends can
+/// extrapolate the true counts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    record_trace: bool,
+    policy: Option<Cow<'static, str>>,
+    rule: Option<Cow<'static, str>>,
+    probability: f64,
+}
+
+impl Decision {
+    /// Export the trace.
+    pub fn keep() -> Self {
+        Decision {
+            record_trace: true,
+            policy: None,
+            rule: None,
+            probability: 1.0,
+        }
+    }
+
+    /// Discard the trace.
+    pub fn drop() -> Self {
+        Decision {
+            record_trace: false,
+            ..Self::keep()
+        }
+    }
+
+    /// Names the policy that reached this decision, e.g. `"error"`.
+    pub fn with_policy<P>(self, policy: P) -> Self
+    where
+        P: Into<Cow<'static, str>>,
+    {
+        Decision {
+            policy: Some(policy.into()),
+            ..self
+        }
+    }
+
+    /// Describes the rule of the policy that matched, e.g. `"slow:/send"`.
+    pub fn with_rule<R>(self, rule: R) -> Self
+    where
+        R: Into<Cow<'static, str>>,
+    {
+        Decision {
+            rule: Some(rule.into()),
+            ..self
+        }
+    }
+
+    /// Records that the trace was kept with `probability`, clamped to `0.0..=1.0`.
+    pub fn with_probability(self, probability: f64) -> Self {
+        Decision {
+            probability: probability.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// Whether the trace is exported.
+    pub fn record_trace(&self) -> bool {
+        self.record_trace
+    }
+
+    /// The policy that reached this decision, if named.
+    pub fn policy(&self) -> Option<&str> {
+        self.policy.as_deref()
+    }
+
+    /// The rule of the policy that matched, if described.
+    pub fn rule(&self) -> Option<&str> {
+        self.rule.as_deref()
+    }
+
+    /// The probability with which the trace was kept, 1 unless a sampler reported otherwise.
+    pub fn probability(&self) -> f64 {
+        self.probability
+    }
+
+    /// The number of traces a kept trace stands for, if it was kept with a probability below 1.
+    pub fn adjusted_count(&self) -> Option<f64> {
+        if self.record_trace && 0.0 < self.probability && self.probability < 1.0 {
+            Some(1.0 / self.probability)
+        } else {
+            None
+        }
+    }
+}
+
+/// Decides whether a complete trace is exported.
+///
+/// Samplers are evaluated once per trace, when its root span closes, and see every span of the
+/// trace buffered up to that point.
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::{BufferedTrace, Decision, TailSampler};
+/// use onesignal_tracing_tail_sample::TraceContextLayer;
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// /// Only keeps traces with more than one span.
+/// struct Nested;
+///
+/// impl TailSampler for Nested {
+///     fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+///         if trace.spans().len() > 1 {
+///             Decision::keep()
+///         } else {
+///             Decision::drop()
+///         }
+///     }
+/// }
+///
+/// let telemetry = onesignal_tracing_tail_sample::opentelemetry::layer().with_tail_sampler(Nested);
+///
+/// // Traces are only buffered, and thus tail sampled, below a `TraceContextLayer`.
+/// let subscriber = Registry::default()
+///     .with(TraceContextLayer::default())
+///     .with(telemetry);
+/// # drop(subscriber);
+/// ```
+pub trait TailSampler: Send + Sync {
+    /// Decides whether `trace` is exported.
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision;
+
+    /// Keeps traces kept by both `self` and `other`.
+    ///
+    /// `other` is only evaluated if `self` keeps the trace, which matters for stateful samplers
+    /// such as the [`RateLimitingSampler`].
+    fn and<O>(self, other: O) -> AndSampler<Self, O>
+    where
+        Self: Sized,
+        O: TailSampler,
+    {
+        composite::and(self, other)
+    }
+
+    /// Keeps traces kept by either `self` or `other`.
+    ///
+    /// `other` is only evaluated if `self` drops the trace.
+    fn or<O>(self, other: O) -> OrSampler<Self, O>
+    where
+        Self: Sized,
+        O: TailSampler,
+    {
+        composite::or(self, other)
+    }
+
+    /// Keeps exactly the traces dropped by `self`.
+    fn not(self) -> NotSampler<Self>
+    where
+        Self: Sized,
+    {
+        composite::not(self)
+    }
+}
+
+impl<S: TailSampler + ?Sized> TailSampler for Box<S> {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        (**self).sample(trace)
+    }
+}
+
+impl<S: TailSampler + ?Sized> TailSampler for Arc<S> {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        (**self).sample(trace)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use opentelemetry::trace::{SpanBuilder, SpanContext, TraceFlags};
+    use opentelemetry::Context as OtelContext;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::SystemTime;
+
+    static NEXT_SPAN_ID: AtomicU64 = AtomicU64::new(1);
+
+    /// A closed span without a parent, named `name` both in `tracing` and OpenTelemetry.
+    pub(crate) fn span(name: &'static str) -> BufferedSpan {
+        let now = SystemTime::now();
+        BufferedSpan {
+            data: OtelData {
+                builder: SpanBuilder::from_name(name)
+                    .with_span_id(SpanId::from(NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed)))
+                    .with_start_time(now)
+                    .with_end_time(now),
+                parent_cx: OtelContext::new(),
+            },
+            name,
+            target: module_path!(),
+        }
+    }
+
+    /// A closed span named `name`, whose parent is `parent`.
+    pub(crate) fn child_of(parent: &BufferedSpan, name: &'static str) -> BufferedSpan {
+        let mut child = span(name);
+        let parent_context = SpanContext::new(
+            TraceId::from(1u128),
+            parent.span_id(),
+            TraceFlags::SAMPLED,
+            false,
+            Default::default(),
+        );
+        child.data.parent_cx = OtelContext::new().with_remote_span_context(parent_context);
+        child
+    }
+
+    #[test]
+    fn root_closes_last() {
+        let root = span("root");
+        let spans = [child_of(&root, "child"), root];
+        let trace = BufferedTrace::new(&spans);
+        assert_eq!(trace.root().map(|root| root.name), Some("root"));
+        assert!(BufferedTrace::partial(&spans[..1]).root().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "a trace contains at least its root span")]
+    fn empty_trace() {
+        BufferedTrace::new(&[]);
+    }
+}
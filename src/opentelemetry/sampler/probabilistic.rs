@@ -0,0 +1,102 @@
+use crate::opentelemetry::sampler::{BufferedTrace, Decision, TailSampler};
+use opentelemetry::trace::TraceId;
+
+/// Keeps a fixed ratio of traces, decided from their trace id.
+///
+/// Like the OpenTelemetry SDK's `TraceIdRatioBased` sampler, the decision is derived from the
+/// random low bits of the trace id rather than from a random draw. Every process sampling with
+/// the same ratio thus reaches the same decision for a given trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceIdRatioSampler {
+    ratio: f64,
+}
+
+impl TraceIdRatioSampler {
+    /// Create a sampler keeping `ratio` of all traces, clamped to `0.0..=1.0`.
+    pub fn new(ratio: f64) -> Self {
+        TraceIdRatioSampler {
+            ratio: ratio.clamp(0.0, 1.0),
+        }
+    }
+
+    /// The ratio of traces kept.
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+}
+
+impl TailSampler for TraceIdRatioSampler {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        if sample_trace_id(trace.trace_id(), self.ratio) {
+            Decision::keep()
+                .with_policy("probabilistic")
+                .with_probability(self.ratio)
+        } else {
+            Decision::drop()
+        }
+    }
+}
+
+/// Whether `trace_id` falls within the kept `ratio` of the trace id space.
+pub(crate) fn sample_trace_id(trace_id: TraceId, ratio: f64) -> bool {
+    if ratio >= 1.0 {
+        return true;
+    }
+
+    let prob_upper_bound = (ratio.max(0.0) * (1u64 << 63) as f64) as u64;
+    let rnd_from_trace_id = (u128::from_be_bytes(trace_id.to_bytes()) as u64) >> 1;
+    rnd_from_trace_id < prob_upper_bound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt};
+    use opentelemetry::Context as OtelContext;
+
+    #[test]
+    fn extremes() {
+        for id in [0u128, 1, u64::MAX as u128, u128::MAX] {
+            assert!(sample_trace_id(TraceId::from(id), 1.0));
+            assert!(!sample_trace_id(TraceId::from(id), 0.0));
+        }
+    }
+
+    #[test]
+    fn approximates_ratio() {
+        let kept = (0..10_000u64)
+            .map(|i| TraceId::from((i.wrapping_mul(0x9E37_79B9_7F4A_7C15)) as u128))
+            .filter(|id| sample_trace_id(*id, 0.25))
+            .count();
+
+        assert!((2_300..2_700).contains(&kept), "kept {}", kept);
+    }
+
+    #[test]
+    fn decides_from_trace_id() {
+        let sampler = TraceIdRatioSampler::new(0.5);
+        let low = TraceId::from(1u128 << 32);
+        let high = TraceId::from(u64::MAX as u128);
+
+        let mut root = span("root");
+        root.data.builder.trace_id = Some(low);
+        let spans = [root];
+        let decision = sampler.sample(&BufferedTrace::new(&spans));
+        assert!(decision.record_trace());
+        assert_eq!(decision.adjusted_count(), Some(2.0));
+
+        // Remote parents carry the trace id instead of the root's builder.
+        let mut root = span("root");
+        root.data.builder.trace_id = Some(low);
+        root.data.parent_cx = OtelContext::new().with_remote_span_context(SpanContext::new(
+            high,
+            SpanId::from(1u64),
+            Default::default(),
+            true,
+            Default::default(),
+        ));
+        let spans = [root];
+        assert!(!sampler.sample(&BufferedTrace::new(&spans)).record_trace());
+    }
+}
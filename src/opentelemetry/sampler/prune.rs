@@ -0,0 +1,174 @@
+use crate::opentelemetry::sampler::BufferedSpan;
+use opentelemetry::trace::SpanId;
+use opentelemetry::Context as OtelContext;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// Removes low-value spans from the traces that are kept.
+///
+/// A span is pruned if any of the configured rules matches it. The root span of a trace is never
+/// pruned. The children of a pruned span are reparented onto its nearest kept ancestor, so that
+/// the exported trace stays connected.
+///
+/// Spans are pruned when the buffered spans of a kept trace are exported. Spans closing after the
+/// trace was decided are exported as is.
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::SpanPruner;
+/// use std::time::Duration;
+///
+/// let pruner = SpanPruner::new()
+///     .shorter_than(Duration::from_millis(1))
+///     .target("hyper");
+/// # drop(pruner);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SpanPruner {
+    shorter_than: Option<Duration>,
+    targets: Vec<Cow<'static, str>>,
+}
+
+impl SpanPruner {
+    /// Create a pruner without rules, which keeps every span.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prune spans open for less than `duration`.
+    pub fn shorter_than(self, duration: Duration) -> Self {
+        SpanPruner {
+            shorter_than: Some(duration),
+            ..self
+        }
+    }
+
+    /// Prune spans whose target is `target`, or a module within `target`. Pruning `"hyper"`
+    /// prunes the spans of `hyper::client`, but not those of `hyperlocal`.
+    pub fn target<T>(mut self, target: T) -> Self
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        self.targets.push(target.into());
+        self
+    }
+
+    /// Whether `span` is pruned from the exported trace.
+    pub fn prunes(&self, span: &BufferedSpan) -> bool {
+        let short = match (self.shorter_than, span.duration()) {
+            (Some(min), Some(duration)) => duration < min,
+            _ => false,
+        };
+
+        short
+            || self.targets.iter().any(|target| {
+                span.target
+                    .strip_prefix(target.as_ref())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+    }
+
+    /// Prunes `spans`, the buffered spans of a trace whose root is the last element, unless the
+    /// trace is `partial` and its root still open.
+    pub(crate) fn prune(&self, spans: &mut VecDeque<BufferedSpan>, partial: bool) {
+        let root = if partial {
+            None
+        } else {
+            spans.len().checked_sub(1)
+        };
+        let mut pruned: HashMap<SpanId, OtelContext> = HashMap::new();
+        let mut index = 0;
+        spans.retain(|span| {
+            let keep = Some(index) == root || !self.prunes(span);
+            if !keep {
+                pruned.insert(span.span_id(), span.data.parent_cx.clone());
+            }
+            index += 1;
+            keep
+        });
+
+        for span in spans.iter_mut() {
+            // Bounded by the pruned span count, in case span ids are not unique.
+            for _ in 0..pruned.len() {
+                match span.parent_span_id().and_then(|id| pruned.get(&id)) {
+                    Some(parent_cx) => span.data.parent_cx = parent_cx.clone(),
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::{child_of, span};
+    use std::time::SystemTime;
+
+    fn with_duration(mut span: BufferedSpan, duration: Duration) -> BufferedSpan {
+        let start = SystemTime::now();
+        span.data.builder.start_time = Some(start);
+        span.data.builder.end_time = Some(start + duration);
+        span
+    }
+
+    #[test]
+    fn prunes_by_target() {
+        let pruner = SpanPruner::new().target("hyper");
+        let mut client = span("client");
+        client.target = "hyper::client";
+        let mut hyper = span("hyper");
+        hyper.target = "hyper";
+        let mut other = span("other");
+        other.target = "hyperlocal";
+
+        assert!(pruner.prunes(&client));
+        assert!(pruner.prunes(&hyper));
+        assert!(!pruner.prunes(&other));
+    }
+
+    #[test]
+    fn prunes_by_duration() {
+        let pruner = SpanPruner::new().shorter_than(Duration::from_millis(1));
+
+        assert!(pruner.prunes(&with_duration(span("fast"), Duration::from_micros(10))));
+        assert!(!pruner.prunes(&with_duration(span("slow"), Duration::from_millis(5))));
+        assert!(!SpanPruner::new().prunes(&span("any")));
+    }
+
+    #[test]
+    fn reparents_onto_kept_ancestor() {
+        let slow = Duration::from_millis(5);
+        let root = with_duration(span("root"), slow);
+        let middle = child_of(&root, "middle");
+        let inner = child_of(&middle, "inner");
+        let leaf = with_duration(child_of(&inner, "leaf"), slow);
+        let sibling = with_duration(child_of(&root, "sibling"), slow);
+        let root_id = root.span_id();
+
+        let mut spans = VecDeque::from(vec![leaf, inner, middle, sibling, root]);
+        SpanPruner::new()
+            .shorter_than(Duration::from_millis(1))
+            .prune(&mut spans, false);
+
+        let names: Vec<_> = spans.iter().map(|span| span.name).collect();
+        assert_eq!(names, ["leaf", "sibling", "root"]);
+        assert_eq!(spans[0].parent_span_id(), Some(root_id));
+        assert_eq!(spans[1].parent_span_id(), Some(root_id));
+    }
+
+    #[test]
+    fn keeps_root() {
+        let pruner = SpanPruner::new().shorter_than(Duration::from_secs(1));
+        let mut spans = VecDeque::from(vec![span("root")]);
+        pruner.prune(&mut spans, false);
+        assert_eq!(spans.len(), 1);
+
+        // The last span of a partial trace is not its root.
+        let mut spans = VecDeque::from(vec![span("child")]);
+        pruner.prune(&mut spans, true);
+        assert!(spans.is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+use crate::opentelemetry::sampler::{BufferedTrace, Decision, TailSampler};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What a [`RateLimitingSampler`] counts against its limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimit {
+    /// At most this many traces are kept per second.
+    TracesPerSecond(f64),
+    /// At most this many spans are kept per second, counting every span of a kept trace.
+    ///
+    /// A trace with more spans than the bucket holds, one second worth of spans unless set with
+    /// [`RateLimitingSampler::with_burst`], is always dropped.
+    SpansPerSecond(f64),
+}
+
+/// Caps the throughput of the traces kept by another sampler.
+///
+/// Traces kept by the inner sampler draw from a token bucket refilled at the configured rate,
+/// holding up to one second worth of tokens by default. Once the bucket runs dry, traces are
+/// dropped regardless of the inner decision, which bounds the export volume during error storms.
+///
+/// Kept traces report the ratio of the traces kept by the inner sampler that passed the limit
+/// over the last second as their probability, on top of the inner probability.
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::{ErrorSampler, RateLimitingSampler, RateLimit};
+///
+/// // Keep failing traces, but never more than 100 per second.
+/// let sampler = RateLimitingSampler::new(ErrorSampler, RateLimit::TracesPerSecond(100.0));
+/// # drop(sampler);
+/// ```
+#[derive(Debug)]
+pub struct RateLimitingSampler<S> {
+    inner: S,
+    limit: RateLimit,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    bucket: TokenBucket,
+    window: AdmitRatio,
+}
+
+/// The ratio of traces let through by the bucket, over a sliding one second window.
+#[derive(Debug)]
+struct AdmitRatio {
+    window_start: Instant,
+    offered: u64,
+    admitted: u64,
+    /// The ratio over the previous window.
+    previous: Option<f64>,
+}
+
+impl AdmitRatio {
+    fn record(&mut self, admitted: bool, now: Instant) {
+        if now.saturating_duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.previous = Some(self.current());
+            self.window_start = now;
+            self.offered = 0;
+            self.admitted = 0;
+        }
+
+        self.offered += 1;
+        self.admitted += admitted as u64;
+    }
+
+    fn current(&self) -> f64 {
+        if self.offered == 0 {
+            1.0
+        } else {
+            self.admitted as f64 / self.offered as f64
+        }
+    }
+
+    /// The estimated probability with which a trace is let through.
+    fn ratio(&self) -> f64 {
+        match self.previous {
+            Some(previous) => (previous + self.current()) / 2.0,
+            None => self.current(),
+        }
+    }
+}
+
+impl<S> RateLimitingSampler<S> {
+    /// Limit the traces kept by `inner` to `limit`.
+    pub fn new(inner: S, limit: RateLimit) -> Self {
+        let rate = match limit {
+            RateLimit::TracesPerSecond(rate) | RateLimit::SpansPerSecond(rate) => rate,
+        };
+
+        RateLimitingSampler {
+            inner,
+            limit,
+            state: Mutex::new(State {
+                bucket: TokenBucket::new(rate, rate),
+                window: AdmitRatio {
+                    window_start: Instant::now(),
+                    offered: 0,
+                    admitted: 0,
+                    previous: None,
+                },
+            }),
+        }
+    }
+
+    /// Sets how many traces or spans the bucket holds at most, i.e. how many can be kept at once
+    /// after a quiet period. Traces with more spans than `burst` are never kept under a
+    /// [`RateLimit::SpansPerSecond`] limit.
+    pub fn with_burst(self, burst: f64) -> Self {
+        let mut state = self.state.into_inner().expect("Mutex poisoned");
+        state.bucket = TokenBucket::new(state.bucket.rate, burst);
+        RateLimitingSampler {
+            state: Mutex::new(state),
+            ..self
+        }
+    }
+}
+
+impl<S: TailSampler> RateLimitingSampler<S> {
+    fn sample_at(&self, trace: &BufferedTrace<'_>, now: Instant) -> Decision {
+        let decision = self.inner.sample(trace);
+        if !decision.record_trace() {
+            return decision;
+        }
+
+        let cost = match self.limit {
+            RateLimit::TracesPerSecond(_) => 1.0,
+            RateLimit::SpansPerSecond(_) => trace.spans().len() as f64,
+        };
+
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        let admitted = state.bucket.try_acquire(cost, now);
+        state.window.record(admitted, now);
+
+        if admitted {
+            let probability = decision.probability() * state.window.ratio();
+            decision.with_probability(probability)
+        } else {
+            Decision::drop()
+        }
+    }
+}
+
+impl<S: TailSampler> TailSampler for RateLimitingSampler<S> {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        self.sample_at(trace, Instant::now())
+    }
+}
+
+/// A token bucket refilled at `rate` tokens per second, holding at most `capacity` tokens.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: f64, capacity: f64) -> Self {
+        let capacity = capacity.max(0.0);
+        TokenBucket {
+            rate: rate.max(0.0),
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes `cost` tokens out of the bucket, if that many are available at `now`. A cost above
+    /// the capacity of the bucket is never available.
+    pub(crate) fn try_acquire(&mut self, cost: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use crate::opentelemetry::sampler::ErrorSampler;
+    use opentelemetry::trace as otel;
+    use std::time::Duration;
+
+    struct KeepAll;
+
+    impl TailSampler for KeepAll {
+        fn sample(&self, _trace: &BufferedTrace<'_>) -> Decision {
+            Decision::keep()
+        }
+    }
+
+    #[test]
+    fn token_bucket_refills() {
+        let mut bucket = TokenBucket::new(2.0, 2.0);
+        let start = bucket.last;
+
+        assert!(bucket.try_acquire(1.0, start));
+        assert!(bucket.try_acquire(1.0, start));
+        assert!(!bucket.try_acquire(1.0, start));
+        assert!(bucket.try_acquire(1.0, start + Duration::from_millis(500)));
+
+        // Never holds more than its capacity, so a larger cost is never available.
+        let later = start + Duration::from_secs(10);
+        assert!(!bucket.try_acquire(3.0, later));
+        assert!(bucket.try_acquire(2.0, later));
+        assert!(!bucket.try_acquire(1.0, later));
+
+        let mut burst = TokenBucket::new(2.0, 4.0);
+        assert!(burst.try_acquire(3.0, start));
+        assert!(!burst.try_acquire(3.0, start));
+        assert!(burst.try_acquire(3.0, start + Duration::from_secs(1)));
+
+        let mut empty = TokenBucket::new(0.0, 0.0);
+        assert!(!empty.try_acquire(1.0, start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn limits_traces() {
+        let sampler = RateLimitingSampler::new(KeepAll, RateLimit::TracesPerSecond(2.0));
+        let spans = [span("child"), span("root")];
+        let trace = BufferedTrace::new(&spans);
+        let now = Instant::now();
+
+        let kept = (0..5)
+            .filter(|_| sampler.sample_at(&trace, now).record_trace())
+            .count();
+        assert_eq!(kept, 2);
+    }
+
+    #[test]
+    fn limits_spans() {
+        let sampler = RateLimitingSampler::new(KeepAll, RateLimit::SpansPerSecond(5.0));
+        let spans = [span("child"), span("root")];
+        let trace = BufferedTrace::new(&spans);
+        let now = Instant::now();
+
+        let kept = (0..5)
+            .filter(|_| sampler.sample_at(&trace, now).record_trace())
+            .count();
+        assert_eq!(kept, 2);
+    }
+
+    #[test]
+    fn drops_traces_larger_than_burst() {
+        let spans = [span("a"), span("b"), span("root")];
+        let trace = BufferedTrace::new(&spans);
+        let start = Instant::now();
+
+        // A full bucket holds one second worth of spans.
+        let sampler = RateLimitingSampler::new(KeepAll, RateLimit::SpansPerSecond(2.0));
+        assert!(!sampler.sample_at(&trace, start).record_trace());
+        let later = start + Duration::from_secs(10);
+        assert!(!sampler.sample_at(&trace, later).record_trace());
+
+        let sampler =
+            RateLimitingSampler::new(KeepAll, RateLimit::SpansPerSecond(2.0)).with_burst(4.0);
+        assert!(sampler.sample_at(&trace, start).record_trace());
+        assert!(!sampler.sample_at(&trace, start).record_trace());
+        assert!(sampler.sample_at(&trace, later).record_trace());
+    }
+
+    #[test]
+    fn reports_admit_ratio() {
+        let sampler = RateLimitingSampler::new(KeepAll, RateLimit::TracesPerSecond(2.0));
+        let spans = [span("root")];
+        let trace = BufferedTrace::new(&spans);
+        let start = Instant::now();
+
+        assert_eq!(sampler.sample_at(&trace, start).probability(), 1.0);
+        assert_eq!(sampler.sample_at(&trace, start).probability(), 1.0);
+        for _ in 0..6 {
+            assert!(!sampler.sample_at(&trace, start).record_trace());
+        }
+
+        // A quarter of the traces passed the limit in the last window, and all of the first one
+        // of this window.
+        let decision = sampler.sample_at(&trace, start + Duration::from_secs(1));
+        assert!(decision.record_trace());
+        assert_eq!(decision.probability(), (0.25 + 1.0) / 2.0);
+        assert_eq!(decision.adjusted_count(), Some(1.6));
+    }
+
+    #[test]
+    fn dropped_traces_are_free() {
+        let sampler = RateLimitingSampler::new(ErrorSampler, RateLimit::TracesPerSecond(1.0));
+        let ok = [span("root")];
+        let mut failed = span("root");
+        failed.data.builder.status = otel::Status::error("boom");
+        let failed = [failed];
+        let now = Instant::now();
+
+        let keeps = |spans| {
+            sampler
+                .sample_at(&BufferedTrace::new(spans), now)
+                .record_trace()
+        };
+        assert!(!keeps(&ok));
+        assert!(keeps(&failed));
+        assert!(!keeps(&failed));
+    }
+}
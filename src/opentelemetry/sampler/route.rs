@@ -0,0 +1,127 @@
+use crate::opentelemetry::sampler::{find_by_name, AlwaysOn, BufferedTrace, Decision, TailSampler};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Routes traces to different samplers depending on the name of their root span.
+///
+/// Routes are looked up by the `otel.name` of the root span first, then by the name of the
+/// `tracing` span. Traces without a matching route, and partial traces whose root span is still
+/// open, go to the default sampler, which keeps everything unless replaced with
+/// [`RouteSampler::with_default`].
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::{
+///     AlwaysOff, AlwaysOn, ErrorSampler, RouteSampler, TailSampler, TraceIdRatioSampler,
+/// };
+///
+/// let sampler = RouteSampler::new()
+///     .route("/healthz", AlwaysOff)
+///     .route("POST /notifications", ErrorSampler.or(TraceIdRatioSampler::new(0.01)))
+///     .route("background_job", AlwaysOn)
+///     .with_default(TraceIdRatioSampler::new(0.1));
+/// # drop(sampler);
+/// ```
+pub struct RouteSampler {
+    routes: HashMap<Cow<'static, str>, Box<dyn TailSampler>>,
+    default: Box<dyn TailSampler>,
+}
+
+impl RouteSampler {
+    /// Create a routing table without routes, keeping every trace.
+    pub fn new() -> Self {
+        RouteSampler {
+            routes: HashMap::new(),
+            default: Box::new(AlwaysOn),
+        }
+    }
+
+    /// Samples traces whose root span is named `name` with `sampler`.
+    pub fn route<N, P>(mut self, name: N, sampler: P) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+        P: TailSampler + 'static,
+    {
+        self.routes.insert(name.into(), Box::new(sampler));
+        self
+    }
+
+    /// Samples traces without a matching route with `sampler`.
+    pub fn with_default<P>(self, sampler: P) -> Self
+    where
+        P: TailSampler + 'static,
+    {
+        RouteSampler {
+            default: Box::new(sampler),
+            ..self
+        }
+    }
+}
+
+impl Default for RouteSampler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TailSampler for RouteSampler {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        trace
+            .root()
+            .and_then(|root| find_by_name(&self.routes, root))
+            .unwrap_or(&self.default)
+            .sample(trace)
+    }
+}
+
+impl std::fmt::Debug for RouteSampler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RouteSampler")
+            .field("routes", &self.routes.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::span;
+    use crate::opentelemetry::sampler::AlwaysOff;
+
+    fn keeps(sampler: &RouteSampler, name: &'static str, otel_name: &'static str) -> bool {
+        let mut root = span(name);
+        root.data.builder.name = otel_name.into();
+        let spans = [root];
+        sampler.sample(&BufferedTrace::new(&spans)).record_trace()
+    }
+
+    #[test]
+    fn routes_by_name() {
+        let sampler = RouteSampler::new()
+            .route("/healthz", AlwaysOff)
+            .route("request", AlwaysOff)
+            .route("POST /send", AlwaysOn);
+
+        assert!(!keeps(&sampler, "/healthz", "/healthz"));
+        assert!(!keeps(&sampler, "request", "GET /"));
+        assert!(keeps(&sampler, "request", "POST /send"));
+        assert!(keeps(&sampler, "job", "job"));
+    }
+
+    #[test]
+    fn falls_back_to_default() {
+        let sampler = RouteSampler::new()
+            .route("job", AlwaysOn)
+            .with_default(AlwaysOff);
+
+        assert!(keeps(&sampler, "job", "job"));
+        assert!(!keeps(&sampler, "request", "request"));
+
+        // The last span of a partial trace is not its root.
+        let spans = [span("job")];
+        assert!(!sampler
+            .sample(&BufferedTrace::partial(&spans))
+            .record_trace());
+    }
+}
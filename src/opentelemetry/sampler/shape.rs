@@ -0,0 +1,216 @@
+use crate::opentelemetry::sampler::{BufferedTrace, Decision, TailSampler};
+use opentelemetry::trace::SpanId;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Keeps traces based on their structure.
+///
+/// A trace is kept if any of the configured conditions holds for it. Depth is computed from the
+/// OpenTelemetry span ids, and thus requires a tracer assigning valid ids.
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::TraceShapeSampler;
+///
+/// // Keep runaway traces, and any trace that had to fall back to the legacy backend.
+/// let sampler = TraceShapeSampler::new()
+///     .span_count_above(1000)
+///     .depth_above(20)
+///     .containing("legacy_fallback");
+/// # drop(sampler);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TraceShapeSampler {
+    span_count_above: Option<usize>,
+    span_count_below: Option<usize>,
+    depth_above: Option<usize>,
+    containing: Vec<Cow<'static, str>>,
+}
+
+impl TraceShapeSampler {
+    /// Create a sampler without conditions, which drops every trace.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep traces with more than `count` spans.
+    pub fn span_count_above(self, count: usize) -> Self {
+        TraceShapeSampler {
+            span_count_above: Some(count),
+            ..self
+        }
+    }
+
+    /// Keep traces with fewer than `count` spans.
+    pub fn span_count_below(self, count: usize) -> Self {
+        TraceShapeSampler {
+            span_count_below: Some(count),
+            ..self
+        }
+    }
+
+    /// Keep traces nested more than `depth` spans deep. A lone root span has a depth of 1.
+    pub fn depth_above(self, depth: usize) -> Self {
+        TraceShapeSampler {
+            depth_above: Some(depth),
+            ..self
+        }
+    }
+
+    /// Keep traces containing a span named `name`, either by `otel.name` or by the name of the
+    /// `tracing` span.
+    pub fn containing<N>(mut self, name: N) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+    {
+        self.containing.push(name.into());
+        self
+    }
+}
+
+impl TailSampler for TraceShapeSampler {
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        let count = trace.spans().len();
+        let rule = if let Some(n) = self.span_count_above.filter(|n| count > *n) {
+            format!("span_count>{}", n)
+        } else if let Some(n) = self.span_count_below.filter(|n| count < *n) {
+            format!("span_count<{}", n)
+        } else if let Some(n) = self.depth_above.filter(|n| depth(trace) > *n) {
+            format!("depth>{}", n)
+        } else if let Some(name) = self.containing.iter().find(|name| {
+            trace
+                .spans()
+                .iter()
+                .any(|span| span.data.builder.name == **name || span.name == *name)
+        }) {
+            format!("contains:{}", name)
+        } else {
+            return Decision::drop();
+        };
+
+        Decision::keep().with_policy("shape").with_rule(rule)
+    }
+}
+
+/// The number of spans on the longest path from the root of `trace` to one of its leaves.
+fn depth(trace: &BufferedTrace<'_>) -> usize {
+    let parents: HashMap<SpanId, Option<SpanId>> = trace
+        .spans()
+        .iter()
+        .map(|span| (span.span_id(), span.parent_span_id()))
+        .collect();
+
+    // The depth of every span, so that each ancestor chain is walked once.
+    let mut depths: HashMap<SpanId, usize> = HashMap::with_capacity(parents.len());
+    let mut path = Vec::new();
+    for span in trace.spans() {
+        let mut id = span.span_id();
+        let mut base = 0;
+        path.clear();
+        loop {
+            if let Some(depth) = depths.get(&id) {
+                base = *depth;
+                break;
+            }
+            // Bounded by the span count, in case span ids are not unique.
+            if path.len() >= parents.len() {
+                break;
+            }
+            path.push(id);
+            match parents.get(&id) {
+                Some(Some(parent)) if parents.contains_key(parent) => id = *parent,
+                _ => break,
+            }
+        }
+
+        for (above, id) in path.iter().rev().enumerate() {
+            depths.insert(*id, base + above + 1);
+        }
+    }
+
+    depths.values().copied().max().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::{child_of, span};
+    use crate::opentelemetry::sampler::BufferedSpan;
+
+    fn keeps(sampler: &TraceShapeSampler, spans: &[BufferedSpan]) -> bool {
+        sampler.sample(&BufferedTrace::new(spans)).record_trace()
+    }
+
+    #[test]
+    fn span_count() {
+        let spans = [span("a"), span("b"), span("root")];
+
+        assert!(keeps(&TraceShapeSampler::new().span_count_above(2), &spans));
+        assert!(!keeps(
+            &TraceShapeSampler::new().span_count_above(3),
+            &spans
+        ));
+        assert!(keeps(&TraceShapeSampler::new().span_count_below(4), &spans));
+        assert!(!keeps(
+            &TraceShapeSampler::new().span_count_below(3),
+            &spans
+        ));
+    }
+
+    #[test]
+    fn depth() {
+        let root = span("root");
+        let child = child_of(&root, "child");
+        let grandchild = child_of(&child, "grandchild");
+        let sibling = child_of(&root, "sibling");
+        let spans = [grandchild, child, sibling, root];
+
+        assert_eq!(super::depth(&BufferedTrace::new(&spans)), 3);
+        assert!(keeps(&TraceShapeSampler::new().depth_above(2), &spans));
+        assert!(!keeps(&TraceShapeSampler::new().depth_above(3), &spans));
+    }
+
+    #[test]
+    fn deep_trace() {
+        let mut spans = vec![span("root")];
+        for _ in 1..10_000 {
+            let child = child_of(spans.last().unwrap(), "child");
+            spans.push(child);
+        }
+        spans.reverse();
+
+        assert_eq!(super::depth(&BufferedTrace::new(&spans)), 10_000);
+    }
+
+    #[test]
+    fn depth_with_duplicate_ids() {
+        let root = span("root");
+        let child = child_of(&root, "child");
+        let spans = [child.clone(), child, root];
+
+        assert!(super::depth(&BufferedTrace::new(&spans)) <= spans.len());
+    }
+
+    #[test]
+    fn containing() {
+        let mut child = span("query");
+        child.data.builder.name = "SELECT users".into();
+        let spans = [child, span("root")];
+
+        assert!(keeps(&TraceShapeSampler::new().containing("query"), &spans));
+        assert!(keeps(
+            &TraceShapeSampler::new().containing("SELECT users"),
+            &spans
+        ));
+        assert!(!keeps(
+            &TraceShapeSampler::new().containing("INSERT"),
+            &spans
+        ));
+        assert!(!keeps(&TraceShapeSampler::new(), &spans));
+
+        let sampler = TraceShapeSampler::new().containing("query");
+        let decision = sampler.sample(&BufferedTrace::new(&spans));
+        assert_eq!(decision.rule(), Some("contains:query"));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::opentelemetry::sampler::{BufferedSpan, BufferedTrace, Decision, TailSampler};
+use opentelemetry::trace::{Event, SpanId, SpanKind, Status, TraceId};
+use opentelemetry::{KeyValue, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// A read-only view of a buffered trace, with the relationships between its spans.
+///
+/// Relationships are rebuilt from the OpenTelemetry span ids, and thus require a tracer
+/// assigning valid ids.
+#[derive(Debug)]
+pub struct TraceView<'a> {
+    trace: BufferedTrace<'a>,
+    by_id: HashMap<SpanId, usize>,
+    children: HashMap<SpanId, Vec<usize>>,
+}
+
+impl<'a> TraceView<'a> {
+    /// Indexes the spans of `trace`.
+    pub fn new(trace: BufferedTrace<'a>) -> Self {
+        let mut by_id = HashMap::new();
+        let mut children: HashMap<SpanId, Vec<usize>> = HashMap::new();
+        for (index, span) in trace.spans().iter().enumerate() {
+            by_id.insert(span.span_id(), index);
+            if let Some(parent) = span.parent_span_id() {
+                children.entry(parent).or_default().push(index);
+            }
+        }
+
+        TraceView {
+            trace,
+            by_id,
+            children,
+        }
+    }
+
+    /// The OpenTelemetry trace id, inherited from the remote parent of the root span if any.
+    pub fn trace_id(&self) -> TraceId {
+        self.trace.trace_id()
+    }
+
+    /// The root span of the trace, unless the trace is partial.
+    pub fn root(&self) -> Option<SpanView<'_>> {
+        self.trace.root().map(|root| self.view(root))
+    }
+
+    /// Whether the trace is sampled before its root span closed.
+    pub fn is_partial(&self) -> bool {
+        self.trace.is_partial()
+    }
+
+    /// All spans of the trace, including the root, in the order they closed.
+    pub fn spans(&self) -> impl Iterator<Item = SpanView<'_>> + '_ {
+        self.trace.spans().iter().map(move |span| self.view(span))
+    }
+
+    /// The span of the trace with the id `span_id`.
+    pub fn span(&self, span_id: SpanId) -> Option<SpanView<'_>> {
+        self.by_id
+            .get(&span_id)
+            .map(|index| self.view(&self.trace.spans()[*index]))
+    }
+
+    fn view<'v>(&'v self, span: &'v BufferedSpan) -> SpanView<'v> {
+        SpanView { trace: self, span }
+    }
+}
+
+/// A read-only view of a span within a [`TraceView`].
+#[derive(Clone, Copy)]
+pub struct SpanView<'a> {
+    trace: &'a TraceView<'a>,
+    span: &'a BufferedSpan,
+}
+
+impl<'a> SpanView<'a> {
+    /// The OpenTelemetry id of the span.
+    pub fn span_id(&self) -> SpanId {
+        self.span.span_id()
+    }
+
+    /// The OpenTelemetry id of the parent span, which may be remote.
+    pub fn parent_span_id(&self) -> Option<SpanId> {
+        self.span.parent_span_id()
+    }
+
+    /// The name of the span, as overridden by `otel.name`.
+    pub fn name(&self) -> &'a str {
+        &self.span.data.builder.name
+    }
+
+    /// The target of the `tracing` span.
+    pub fn target(&self) -> &'static str {
+        self.span.target
+    }
+
+    /// The kind of the span, if set with `otel.kind`.
+    pub fn kind(&self) -> Option<&'a SpanKind> {
+        self.span.data.builder.span_kind.as_ref()
+    }
+
+    /// The status the span ended with.
+    pub fn status(&self) -> &'a Status {
+        &self.span.data.builder.status
+    }
+
+    /// The attributes recorded on the span.
+    pub fn attributes(&self) -> &'a [KeyValue] {
+        self.span
+            .data
+            .builder
+            .attributes
+            .as_deref()
+            .unwrap_or_default()
+    }
+
+    /// The value of the attribute `key`, if recorded on the span.
+    pub fn attribute(&self, key: &str) -> Option<&'a Value> {
+        self.attributes()
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| &attribute.value)
+    }
+
+    /// The events recorded within the span.
+    pub fn events(&self) -> &'a [Event] {
+        self.span.data.builder.events.as_deref().unwrap_or_default()
+    }
+
+    /// When the span was opened.
+    pub fn start_time(&self) -> Option<SystemTime> {
+        self.span.data.builder.start_time
+    }
+
+    /// When the span was closed.
+    pub fn end_time(&self) -> Option<SystemTime> {
+        self.span.data.builder.end_time
+    }
+
+    /// How long the span was open, if both its start and end time were recorded.
+    pub fn duration(&self) -> Option<Duration> {
+        self.span.duration()
+    }
+
+    /// The parent span, unless the span is the root or its parent is remote.
+    pub fn parent(&self) -> Option<SpanView<'a>> {
+        self.parent_span_id().and_then(|id| self.trace.span(id))
+    }
+
+    /// The spans whose parent is this span, in the order they closed.
+    pub fn children(&self) -> impl Iterator<Item = SpanView<'a>> + 'a {
+        let trace = self.trace;
+        trace
+            .children
+            .get(&self.span_id())
+            .into_iter()
+            .flatten()
+            .map(move |index| trace.view(&trace.trace.spans()[*index]))
+    }
+
+    /// The underlying buffered span.
+    pub fn buffered(&self) -> &'a BufferedSpan {
+        self.span
+    }
+}
+
+impl fmt::Debug for SpanView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpanView")
+            .field("span_id", &self.span_id())
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+/// A sampler deciding with a closure over a [`TraceView`].
+///
+/// Created with [`from_fn`], or configured directly with
+/// [`OpenTelemetryLayer::with_sampler`].
+///
+/// [`OpenTelemetryLayer::with_sampler`]: crate::opentelemetry::OpenTelemetryLayer::with_sampler
+#[derive(Clone, Copy)]
+pub struct FnSampler<F> {
+    f: F,
+}
+
+impl<F> fmt::Debug for FnSampler<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FnSampler").finish_non_exhaustive()
+    }
+}
+
+/// Creates a sampler deciding with `f`.
+///
+/// # Examples
+///
+/// ```
+/// use onesignal_tracing_tail_sample::opentelemetry::sampler::{self, Decision, ErrorSampler, TailSampler};
+///
+/// // Keep failing traces, and the ones that went through the cache.
+/// let sampler = ErrorSampler.or(sampler::from_fn(|trace| {
+///     if trace.spans().any(|span| span.name() == "cache.get") {
+///         Decision::keep().with_policy("cache")
+///     } else {
+///         Decision::drop()
+///     }
+/// }));
+/// # drop(sampler);
+/// ```
+pub fn from_fn<F>(f: F) -> FnSampler<F>
+where
+    F: Fn(&TraceView<'_>) -> Decision + Send + Sync,
+{
+    FnSampler { f }
+}
+
+impl<F> TailSampler for FnSampler<F>
+where
+    F: Fn(&TraceView<'_>) -> Decision + Send + Sync,
+{
+    fn sample(&self, trace: &BufferedTrace<'_>) -> Decision {
+        (self.f)(&TraceView::new(*trace))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opentelemetry::sampler::tests::{child_of, span};
+
+    #[test]
+    fn relationships() {
+        let root = span("root");
+        let child = child_of(&root, "child");
+        let grandchild = child_of(&child, "grandchild");
+        let sibling = child_of(&root, "sibling");
+        let spans = [grandchild, child, sibling, root];
+        let view = TraceView::new(BufferedTrace::new(&spans));
+
+        let root = view.root().unwrap();
+        assert_eq!(root.name(), "root");
+        assert!(root.parent().is_none());
+
+        let children: Vec<_> = root.children().map(|span| span.name()).collect();
+        assert_eq!(children, ["child", "sibling"]);
+
+        let grandchild = view.spans().next().unwrap();
+        assert_eq!(grandchild.parent().unwrap().name(), "child");
+        assert_eq!(grandchild.children().count(), 0);
+        assert_eq!(
+            view.span(spans[1].span_id()).map(|span| span.name()),
+            Some("child")
+        );
+    }
+
+    #[test]
+    fn span_data() {
+        let mut root = span("root");
+        root.data.builder.name = "GET /users".into();
+        root.data.builder.span_kind = Some(SpanKind::Server);
+        root.data.builder.attributes = Some(vec![KeyValue::new("http.status_code", 200)]);
+        let spans = [root];
+        let view = TraceView::new(BufferedTrace::new(&spans));
+
+        let root = view.root().unwrap();
+        assert_eq!(root.name(), "GET /users");
+        assert_eq!(root.buffered().name, "root");
+        assert_eq!(root.kind(), Some(&SpanKind::Server));
+        assert_eq!(root.status(), &Status::Unset);
+        assert_eq!(root.attribute("http.status_code"), Some(&Value::I64(200)));
+        assert_eq!(root.attribute("missing"), None);
+        assert!(root.events().is_empty());
+        assert_eq!(root.duration(), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn from_fn() {
+        let sampler = super::from_fn(|trace| {
+            if trace.root().is_some_and(|root| root.children().count() > 1) {
+                Decision::keep()
+            } else {
+                Decision::drop()
+            }
+        });
+
+        let root = span("root");
+        let spans = [child_of(&root, "a"), child_of(&root, "b"), root];
+        assert!(sampler.sample(&BufferedTrace::new(&spans)).record_trace());
+
+        let root = span("root");
+        let spans = [child_of(&root, "a"), root];
+        assert!(!sampler.sample(&BufferedTrace::new(&spans)).record_trace());
+
+        let root = span("root");
+        let spans = [child_of(&root, "a"), child_of(&root, "b")];
+        let view = TraceView::new(BufferedTrace::partial(&spans));
+        assert!(view.is_partial());
+        assert!(view.root().is_none());
+        assert!(!sampler
+            .sample(&BufferedTrace::partial(&spans))
+            .record_trace());
+    }
+}
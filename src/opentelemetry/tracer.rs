@@ -110,7 +110,7 @@ impl PreSampledTracer for Tracer {
                 trace_id,
                 &builder.name,
                 builder.span_kind.as_ref().unwrap_or(&SpanKind::Internal),
-                &builder.attributes.as_ref().unwrap_or(&vec![]),
+                builder.attributes.as_ref().unwrap_or(&vec![]),
                 builder.links.as_deref().unwrap_or(&[]),
             ));
 